@@ -1,38 +1,91 @@
+mod access;
+mod anthropic;
+mod content_filter;
+mod image_generation;
+mod mentions;
+mod pseudonyms;
+mod rate_limit;
+mod sanitize;
+mod tools;
+mod usage;
 mod user_message;
 
-use std::{collections::VecDeque, path::Path, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
 
+use access::Access;
 use anyhow::Context;
 use async_openai::{
     Client as AIClient,
     config::OpenAIConfig,
     types::{
-        ChatChoice, ChatCompletionRequestMessage, ChatCompletionResponseMessage,
-        CreateChatCompletionRequestArgs,
+        ChatChoice, ChatCompletionRequestAssistantMessage, ChatCompletionRequestDeveloperMessage,
+        ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestMessage,
+        ChatCompletionRequestToolMessage, ChatCompletionRequestToolMessageContent,
+        ChatCompletionResponseMessage, CreateChatCompletionRequestArgs, FinishReason, Stop,
     },
 };
-use serde::Deserialize;
+use content_filter::ContentFilter;
+use mentions::Author;
+use metrics::{Counter, counter, histogram};
+use pseudonyms::Pseudonyms;
+use rate_limit::{AdaptiveDelay, retry_after, with_jitter};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
 use tokio::{
-    sync::{broadcast, mpsc},
+    select,
+    sync::{broadcast, mpsc, watch},
     time::{Instant, sleep_until},
 };
-use tracing::{debug, error};
-use twilight_gateway::Event;
+use tools::Tool;
+use tracing::{Instrument, debug, error, info, warn};
+use twilight_cache_inmemory::{InMemoryCache, ResourceType};
+use twilight_gateway::{Event, Intents};
 use twilight_http::Client;
-use twilight_model::id::{Id, marker::ChannelMarker};
-use user_message::queue_messages;
+use twilight_model::{
+    channel::message::AllowedMentions,
+    id::{
+        Id,
+        marker::{ChannelMarker, GuildMarker, MessageMarker, RoleMarker, UserMarker},
+    },
+};
+use usage::{Usage, UsageLog};
+use user_message::{
+    ImageDownloader, Queued, UserMessage, mentions_user, queue_messages, sender_roles,
+};
 
 use crate::{
-    config::file_watch::{load_prompt, monitor_prompt},
-    error::send_error_msg,
+    commands,
+    config::{
+        file_watch::{PROMPT_SEPARATOR, load_prompts, monitor_prompts},
+        read_secret, redact, redact_values,
+    },
+    error::ErrorReporter,
 };
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Configuration {
     channel_id: Id<ChannelMarker>,
+    #[serde(default, serialize_with = "redact")]
     llm_api_key: String,
-    /// The base API endpoint to use. If not set the OpenAI API will be used.
+    /// A file containing the API key, this can be used instead of `llm_api_key`.
+    llm_api_key_file: Option<PathBuf>,
+    /// The kind of API `llm_api_base` serves.
+    #[serde(default)]
+    api_backend: ApiBackend,
+    /// The base API endpoint to use. If not set the official API of `api_backend` will be used.
     llm_api_base: Option<String>,
+    /// Headers sent with every request to the LLM API, for example for proxies that need their own
+    /// authentication or routing headers.
+    #[serde(default, serialize_with = "redact_values")]
+    extra_headers: HashMap<String, String>,
     model_name: String,
     /// The maximum amount of messages to include as history when generating a response. This does
     /// *not* include the channel prompt.
@@ -44,6 +97,10 @@ pub struct Configuration {
     /// The minimum amount of messages that should be kept when downsizing the message history.
     #[serde(default = "default_min_history_size")]
     min_history_size: u32,
+    /// The maximum amount of messages waiting to be responded to. New messages are dropped while
+    /// the queue is full.
+    #[serde(default = "default_queue_capacity")]
+    queue_capacity: usize,
     /// If set to true, the LLM will also be able to see images sent by users. This requires the LLM
     /// used supports images as input.
     ///
@@ -55,20 +112,476 @@ pub struct Configuration {
     /// Images that have one or both dimensions bigger than this value will be downsized.
     #[serde(default = "default_max_image_size")]
     max_image_size: u32,
-    /// The filepath to the prompt used for this channel.
+    /// The filter used to downsize images, sharper filters keep text more readable but are slower.
+    #[serde(default)]
+    image_resize_filter: ResizeFilter,
+    /// The detail the LLM sees images in, higher detail costs more tokens.
+    #[serde(default)]
+    image_detail: ImageDetailLevel,
+    /// The maximum amount of images sent to the LLM per message, the other images are left out.
+    #[serde(default = "default_max_images_per_message")]
+    max_images_per_message: usize,
+    /// The maximum amount of images that are downloaded at the same time.
+    #[serde(default = "default_max_concurrent_image_downloads")]
+    max_concurrent_image_downloads: usize,
+    /// The maximum time in seconds downloading an image may take, failed downloads are retried a
+    /// few times.
+    #[serde(default = "default_image_download_timeout_secs")]
+    image_download_timeout_secs: u64,
+    /// The file extensions of the attachments that are sent to the LLM as images.
     ///
-    /// This should be a plain text file.
-    prompt_path: Box<Path>,
+    /// Only the first frame of animated GIFs is sent.
+    #[serde(default = "default_image_formats")]
+    image_formats: Vec<String>,
+    /// Attachments larger than this amount of bytes are not downloaded.
+    #[serde(default = "default_max_attachment_bytes")]
+    max_attachment_bytes: u64,
+    /// The filepath to the prompt used for this channel, or a list of filepaths.
+    ///
+    /// These should be plain text files. When multiple files are used, they are joined in order
+    /// so later files are appended after earlier ones.
+    #[serde(alias = "system_prompt_path")]
+    prompt_path: PromptPaths,
+    /// A prompt sent as a developer message after the system prompt, for instructions that are
+    /// changed more often than the system prompt.
+    ///
+    /// APIs without the developer role, like the Anthropic API, get it appended to the system
+    /// prompt instead.
+    developer_prompt_path: Option<Box<Path>>,
+    /// Whether the developer prompt is sent with the developer role, otherwise it is appended to
+    /// the system prompt.
+    ///
+    /// Defaults to true for the OpenAI API itself and false when `llm_api_base` is set, as other
+    /// APIs often don't support the role.
+    developer_role: Option<bool>,
+    /// Prompt files larger than this amount of bytes are not read.
+    #[serde(default = "default_max_prompt_bytes")]
+    max_prompt_bytes: u64,
+    /// Alternate prompts used when the author of the most recent message has a certain role.
+    ///
+    /// When the author has multiple of these roles, the first matching entry is used.
+    #[serde(default, rename = "role_prompt")]
+    role_prompts: Vec<RolePrompt>,
+    /// Only members with one of these roles can talk to the AI. When both this and `allowed_users`
+    /// are empty, everyone can.
+    #[serde(default)]
+    allowed_roles: Vec<Id<RoleMarker>>,
+    /// Only these users can talk to the AI, in addition to the members with an allowed role.
+    #[serde(default)]
+    allowed_users: Vec<Id<UserMarker>>,
+    /// These users can never talk to the AI.
+    #[serde(default)]
+    blocked_users: Vec<Id<UserMarker>>,
+    /// Messages from accounts created less than this many seconds ago are ignored.
+    min_account_age_secs: Option<u64>,
+    /// Messages from members that joined the guild less than this many seconds ago are ignored.
+    min_membership_secs: Option<u64>,
+    /// Replaces the built-in format of the user messages sent to the LLM. Placeholders like
+    /// `{author_name}` and `{content}` are replaced by the values of the message.
+    message_template: Option<String>,
+    /// If set to false, the usernames and ids of users are not sent to the LLM. Users are named by
+    /// their display name instead, or a pseudonym when they don't have one. This implies
+    /// `anonymize_ids`.
+    #[serde(default = "default_include_usernames")]
+    include_usernames: bool,
+    /// If set to true, the ids of users are replaced by pseudonyms like `user_1` before they are
+    /// sent to the LLM, both as the sender and in mentions.
+    #[serde(default)]
+    anonymize_ids: bool,
+    /// If set to true, the bot will also respond in threads created under this channel. Each thread
+    /// keeps its own message history.
+    #[serde(default)]
+    include_threads: bool,
+    /// The initial time between two requests to the LLM API in milliseconds. It stays the same
+    /// unless `min_response_delay_ms` or `max_response_delay_ms` is set.
+    ///
+    /// When the API responds that the rate limit has been reached and says how long to wait, that
+    /// delay is used instead.
+    #[serde(default = "default_request_interval_ms")]
+    request_interval_ms: u64,
+    /// The lowest the time between requests can become in milliseconds. The time between requests
+    /// shrinks toward this value while the channel is quiet.
+    ///
+    /// Defaults to `request_interval_ms`.
+    min_response_delay_ms: Option<u64>,
+    /// The highest the time between requests can become in milliseconds. The time between requests
+    /// grows toward this value during bursts of messages, so more messages are answered at once.
+    ///
+    /// Defaults to `request_interval_ms`.
+    max_response_delay_ms: Option<u64>,
+    /// How long failed requests to the LLM API are retried in seconds, with an increasing delay
+    /// between attempts. Rate limited requests wait at least as long as the API asks for.
+    #[serde(default = "default_backoff_max_elapsed_secs")]
+    backoff_max_elapsed_secs: u64,
+    /// Built-in tools the LLM can call, for example to get the current time. This requires the LLM
+    /// used supports tool calling.
+    #[serde(default)]
+    tools: Vec<Tool>,
+    /// If set to true and the LLM chooses not to respond to a message that mentions the bot, the
+    /// response is generated once more with an instruction to reply.
+    #[serde(default)]
+    retry_on_empty_if_mentioned: bool,
+    /// If set to true, responses are sent as a reply to the most recent message they respond to.
+    #[serde(default)]
+    reply_to_trigger: bool,
+    /// When set, this message is sent while the response is generated and is then edited into the
+    /// response.
+    placeholder_message: Option<String>,
+    /// If set to true, the response to a message is generated again and edited when the message
+    /// is edited shortly after.
+    #[serde(default)]
+    edit_reply_on_edit: bool,
+    /// If set to true, responses can ping `@everyone` and `@here`.
+    #[serde(default)]
+    allow_everyone_mentions: bool,
+    /// If set to true, responses can ping roles.
+    #[serde(default)]
+    allow_role_mentions: bool,
+    /// If set to true, the most recent messages in the channel are added to the history when the
+    /// bot starts, so the AI knows what was being talked about.
+    #[serde(default)]
+    seed_history: bool,
+    /// If set to true, `@name` and user ids written by the LLM are converted into mentions of the
+    /// recent authors in the channel.
+    #[serde(default)]
+    convert_mentions: bool,
+    /// When set, the total amount of tokens used by the channel is logged every this many
+    /// responses. The tokens used by every response are logged at the debug level.
+    usage_log_interval: Option<u32>,
+    /// When set, consecutive messages of the same author sent within this many seconds of each
+    /// other are merged into a single message in the history.
+    merge_messages_within_secs: Option<u64>,
+    /// Pairs of opening and closing tags, like `<think>` and `</think>`, whose contents are removed
+    /// from responses. This is used to hide the reasoning of reasoning models.
+    #[serde(default = "default_strip_tags")]
+    strip_tags: Vec<(String, String)>,
+    /// Appended to responses that were cut off, either to fit in a discord message or because the
+    /// LLM reached the maximum amount of tokens. Nothing is appended when empty. The rest of a
+    /// response that was cut off is not sent.
+    #[serde(default = "default_truncation_indicator")]
+    truncation_indicator: String,
+    /// The format the LLM has to respond in.
+    #[serde(default)]
+    response_format: ResponseFormat,
+    /// The LLM stops generating the response when it writes one of these.
+    #[serde(default = "default_stop")]
+    stop: Vec<String>,
+    /// If set to true, the messages that would be sent to the LLM are logged instead, so the prompt
+    /// and history can be checked without using tokens.
+    #[serde(default)]
+    dry_run: bool,
+    /// Blocks words from being sent by the bot.
+    content_filter: Option<content_filter::Configuration>,
+    /// Generates images with a command instead of responding with text.
+    image_generation: Option<image_generation::Configuration>,
+}
+
+/// The APIs that can be used to generate responses.
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ApiBackend {
+    /// The OpenAI chat completions API, also supported by many other providers.
+    #[default]
+    #[serde(rename = "openai")]
+    OpenAI,
+    /// The Anthropic messages API.
+    #[serde(rename = "anthropic")]
+    Anthropic,
+}
+
+/// The formats responses can be generated in.
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Normal chat messages.
+    #[default]
+    Text,
+    /// A JSON object, for integrations that read the responses of the bot. Responses are only
+    /// sent when they are valid JSON and fit in a discord message.
+    JsonObject,
+}
+
+/// The detail images are sent to the LLM in.
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageDetailLevel {
+    /// A low resolution version of the image, this uses few tokens per image.
+    #[default]
+    Low,
+    /// Small details like text in screenshots can be read, but an image uses many more tokens.
+    High,
+    /// The API chooses the detail based on the size of the image.
+    Auto,
+}
+
+/// The filters images can be downsized with, from fastest to sharpest.
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    /// The nearest pixel, this is the fastest but makes edges jagged.
+    Nearest,
+    /// Linear interpolation.
+    #[default]
+    Triangle,
+    /// Cubic interpolation.
+    CatmullRom,
+    Gaussian,
+    /// Keeps small details like text in screenshots the most readable.
+    Lanczos3,
+}
+
+/// One or more prompt files that are joined into a single prompt.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum PromptPaths {
+    Single(Box<Path>),
+    Multiple(Vec<Box<Path>>),
+}
+
+impl PromptPaths {
+    pub fn paths(&self) -> Vec<&Path> {
+        match self {
+            PromptPaths::Single(path) => vec![path.as_ref()],
+            PromptPaths::Multiple(paths) => paths.iter().map(AsRef::as_ref).collect(),
+        }
+    }
 }
 
 impl Configuration {
-    pub fn get_prompt_path(&self) -> &Path {
-        self.prompt_path.as_ref()
+    pub fn get_prompt_paths(&self) -> Vec<&Path> {
+        self.prompt_path.paths()
     }
 
     pub fn get_channel_id(&self) -> &Id<ChannelMarker> {
         &self.channel_id
     }
+
+    pub fn get_include_threads(&self) -> bool {
+        self.include_threads
+    }
+
+    /// Logs the most important settings of the channel.
+    pub fn log_summary(&self) {
+        info!(
+            channel_id = %self.channel_id,
+            model_name = %self.model_name,
+            api_backend = ?self.api_backend,
+            include_threads = self.include_threads,
+            image_support = self.image_support,
+            tools = ?self.tools,
+            content_filter = self.content_filter.is_some(),
+            developer_prompt = self.developer_prompt_path.is_some(),
+            role_prompts = self.role_prompts.len(),
+            "AI channel"
+        );
+    }
+
+    /// Whether the developer prompt is sent as a developer message.
+    fn developer_role(&self) -> bool {
+        self.developer_role.unwrap_or(self.llm_api_base.is_none())
+            && self.api_backend == ApiBackend::OpenAI
+    }
+
+    fn min_response_delay(&self) -> u64 {
+        self.min_response_delay_ms
+            .unwrap_or(self.request_interval_ms)
+    }
+
+    fn max_response_delay(&self) -> u64 {
+        self.max_response_delay_ms
+            .unwrap_or(self.request_interval_ms)
+    }
+
+    /// Reads the secrets that are stored in separate files into their corresponding fields.
+    pub fn read_secrets(&mut self) -> anyhow::Result<()> {
+        read_secret(
+            &mut self.llm_api_key,
+            self.llm_api_key_file.as_deref(),
+            "llm_api_key",
+        )
+        .with_context(|| format!("invalid configuration for AI channel {}", self.channel_id))
+    }
+
+    /// The `extra_headers` sent with every request to the LLM API.
+    fn extra_headers(&self) -> anyhow::Result<HeaderMap> {
+        self.extra_headers
+            .iter()
+            .map(|(name, value)| {
+                let name = HeaderName::try_from(name)
+                    .with_context(|| format!("invalid header name '{name}'"))?;
+                let mut value = HeaderValue::try_from(value)
+                    .with_context(|| format!("invalid value for header '{name}'"))?;
+                value.set_sensitive(true);
+                Ok((name, value))
+            })
+            .collect()
+    }
+
+    /// Checks that the configuration values are sensible.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.api_backend == ApiBackend::OpenAI || self.tools.is_empty(),
+            "`tools` are only supported by the `openai` api backend"
+        );
+        if let Some(content_filter) = &self.content_filter {
+            // Compiled here so an invalid pattern is found before the channel starts.
+            ContentFilter::new(content_filter).context("invalid `content_filter`")?;
+        }
+        if let Some(image_generation) = &self.image_generation {
+            anyhow::ensure!(
+                self.api_backend == ApiBackend::OpenAI,
+                "`image_generation` is only supported by the `openai` api backend"
+            );
+            image_generation
+                .validate()
+                .context("invalid `image_generation`")?;
+        }
+        anyhow::ensure!(
+            self.api_backend == ApiBackend::OpenAI || self.response_format == ResponseFormat::Text,
+            "`response_format` is only supported by the `openai` api backend"
+        );
+        anyhow::ensure!(
+            !self.model_name.is_empty(),
+            "`model_name` must not be empty"
+        );
+        let prompt_paths = self.get_prompt_paths();
+        anyhow::ensure!(!prompt_paths.is_empty(), "`prompt_path` must not be empty");
+        self.extra_headers().context("invalid `extra_headers`")?;
+        anyhow::ensure!(
+            prompt_paths.iter().all(|path| !path.as_os_str().is_empty()),
+            "`prompt_path` must not be empty"
+        );
+        anyhow::ensure!(
+            self.developer_prompt_path
+                .as_ref()
+                .is_none_or(|path| !path.as_os_str().is_empty()),
+            "`developer_prompt_path` must not be empty"
+        );
+        anyhow::ensure!(
+            self.max_history_size > 0,
+            "`max_history_size` must be at least 1"
+        );
+        anyhow::ensure!(
+            self.min_history_size <= self.max_history_size,
+            "`min_history_size` ({}) must not be larger than `max_history_size` ({})",
+            self.min_history_size,
+            self.max_history_size
+        );
+        anyhow::ensure!(
+            self.queue_capacity > 0,
+            "`queue_capacity` must be at least 1"
+        );
+        anyhow::ensure!(
+            self.usage_log_interval != Some(0),
+            "`usage_log_interval` must be at least 1"
+        );
+        anyhow::ensure!(
+            self.image_download_timeout_secs > 0,
+            "`image_download_timeout_secs` must be at least 1"
+        );
+        anyhow::ensure!(
+            self.max_images_per_message > 0,
+            "`max_images_per_message` must be at least 1"
+        );
+        anyhow::ensure!(
+            self.max_concurrent_image_downloads > 0,
+            "`max_concurrent_image_downloads` must be at least 1"
+        );
+        if let Some(template) = &self.message_template {
+            user_message::validate_template(template).context("invalid `message_template`")?;
+        }
+        anyhow::ensure!(
+            self.min_response_delay() <= self.max_response_delay(),
+            "`min_response_delay_ms` ({}) must not be larger than `max_response_delay_ms` ({})",
+            self.min_response_delay(),
+            self.max_response_delay()
+        );
+        for role_prompt in &self.role_prompts {
+            anyhow::ensure!(
+                !role_prompt.prompt_path.as_os_str().is_empty(),
+                "`prompt_path` of the role prompt for {} must not be empty",
+                role_prompt.role_id
+            );
+        }
+        anyhow::ensure!(
+            self.max_image_size > 0,
+            "`max_image_size` must be at least 1"
+        );
+        anyhow::ensure!(
+            self.stop.len() <= MAX_STOP_SEQUENCES,
+            "`stop` can have at most {MAX_STOP_SEQUENCES} sequences"
+        );
+        anyhow::ensure!(
+            self.stop.iter().all(|stop| !stop.is_empty()),
+            "the sequences in `stop` must not be empty"
+        );
+        anyhow::ensure!(
+            self.strip_tags
+                .iter()
+                .all(|(open, close)| !open.is_empty() && !close.is_empty()),
+            "the tags in `strip_tags` must not be empty"
+        );
+        if let Some(placeholder) = &self.placeholder_message {
+            anyhow::ensure!(
+                !placeholder.trim().is_empty() && placeholder.chars().count() <= MAX_MESSAGE_CHARS,
+                "`placeholder_message` must not be empty and at most {MAX_MESSAGE_CHARS} characters"
+            );
+        }
+        anyhow::ensure!(
+            self.truncation_indicator.chars().count() <= MAX_TRUNCATION_INDICATOR_CHARS,
+            "`truncation_indicator` must be at most {MAX_TRUNCATION_INDICATOR_CHARS} characters"
+        );
+        Ok(())
+    }
+
+    /// The gateway intents this channel needs to receive its events.
+    pub fn intents(&self) -> Intents {
+        // Guild events contain the channels and roles needed to check the permissions of commands
+        // sent as messages, and the threads which are needed to find their parent channel.
+        Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT | Intents::GUILDS
+    }
+
+    /// The cache resource types this channel relies on.
+    pub fn resource_types(&self) -> ResourceType {
+        // Used to check the permissions of commands sent as messages. The channels are also used to
+        // find the parent channel of threads, and the members to find their roles when a message
+        // does not include them.
+        ResourceType::GUILD | ResourceType::CHANNEL | ResourceType::ROLE | ResourceType::MEMBER
+    }
+}
+
+/// A prompt used instead of the channel prompt for members with a certain role.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+struct RolePrompt {
+    role_id: Id<RoleMarker>,
+    /// The filepath to the prompt, this should be a plain text file.
+    prompt_path: Box<Path>,
+}
+
+/// AI channel settings that are overridden for a single guild.
+///
+/// Settings that are set here take precedence over the settings of the AI channel, settings that
+/// are not set use the value of the AI channel.
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq)]
+pub struct GuildOverrides {
+    model_name: Option<String>,
+    prompt_path: Option<Box<Path>>,
+}
+
+impl GuildOverrides {
+    /// Checks that the configuration values are sensible.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.model_name.as_ref().is_none_or(|name| !name.is_empty()),
+            "`model_name` must not be empty"
+        );
+        anyhow::ensure!(
+            self.prompt_path
+                .as_ref()
+                .is_none_or(|path| !path.as_os_str().is_empty()),
+            "`prompt_path` must not be empty"
+        );
+        Ok(())
+    }
 }
 
 fn default_max_history_size() -> u32 {
@@ -79,215 +592,1526 @@ fn default_min_history_size() -> u32 {
     30
 }
 
+fn default_queue_capacity() -> usize {
+    20
+}
+
 fn default_max_image_size() -> u32 {
     800
 }
 
+fn default_max_images_per_message() -> usize {
+    4
+}
+
+fn default_max_concurrent_image_downloads() -> usize {
+    4
+}
+
+fn default_image_download_timeout_secs() -> u64 {
+    10
+}
+
+fn default_image_formats() -> Vec<String> {
+    ["jpeg", "jpg", "png", "webp"].map(String::from).to_vec()
+}
+
+fn default_max_attachment_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_request_interval_ms() -> u64 {
+    1500
+}
+
+fn default_max_prompt_bytes() -> u64 {
+    64 * 1024
+}
+
+fn default_backoff_max_elapsed_secs() -> u64 {
+    5
+}
+
+fn default_include_usernames() -> bool {
+    true
+}
+
+fn default_strip_tags() -> Vec<(String, String)> {
+    vec![("<think>".to_string(), "</think>".to_string())]
+}
+
+fn default_stop() -> Vec<String> {
+    vec!["</msg>".to_string()]
+}
+
+fn default_truncation_indicator() -> String {
+    "…".to_string()
+}
+
+/// The settings and state shared by all AI channels.
+pub struct Shared {
+    /// Overrides of the AI channel settings for specific guilds.
+    pub guild_overrides: HashMap<Id<GuildMarker>, GuildOverrides>,
+    pub errors: ErrorReporter,
+    /// The prefix of commands sent as messages, like `!reset`.
+    pub command_prefix: String,
+    /// The users that may use every command, regardless of their permissions.
+    pub owners: Vec<Id<UserMarker>>,
+}
+
+impl Shared {
+    /// Whether the user is one of the owners of the bot.
+    pub fn is_owner(&self, user_id: Id<UserMarker>) -> bool {
+        self.owners.contains(&user_id)
+    }
+}
+
 /// Runs the main AI channel logic.
+///
+/// While `paused` is set, new messages are still received, but not responded to. Returns once
+/// `shutdown` is set to true and the response being generated, if any, is sent.
 pub async fn serve(
     config: Configuration,
     events: broadcast::Receiver<Arc<Event>>,
     http: Arc<Client>,
+    cache: Arc<InMemoryCache>,
+    shared: Arc<Shared>,
+    paused: Arc<AtomicBool>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
-    let (prompt_sender, prompt_receiver) = match load_prompt(config.get_prompt_path()).await {
-        Ok(var) => var,
-        Err(err) => {
-            tracing::error!("Unable to read channel prompt: {err}");
-            tracing::error!(
+    let Some(prompt_receiver) = load_and_monitor_prompt(
+        &config.get_prompt_paths(),
+        config.max_prompt_bytes,
+        config.channel_id,
+    )
+    .await
+    else {
+        tracing::error!(
+            "Channel with id '{}' will not be activated",
+            config.get_channel_id()
+        );
+        return;
+    };
+    let developer_prompt = match &config.developer_prompt_path {
+        Some(path) => {
+            let Some(developer_prompt) =
+                load_and_monitor_prompt(&[path], config.max_prompt_bytes, config.channel_id).await
+            else {
+                tracing::error!(
+                    "Channel with id '{}' will not be activated",
+                    config.get_channel_id()
+                );
+                return;
+            };
+            Some(developer_prompt)
+        }
+        None => None,
+    };
+    // The prompts used by guild overrides and roles, loaded when they are first needed.
+    let mut override_prompts: HashMap<Box<Path>, Option<watch::Receiver<Box<str>>>> =
+        HashMap::new();
+
+    let content_filter = match config.content_filter.as_ref().map(ContentFilter::new) {
+        Some(Ok(content_filter)) => Some(content_filter),
+        Some(Err(err)) => {
+            error!("Invalid content filter: {err:?}");
+            error!(
                 "Channel with id '{}' will not be activated",
                 config.get_channel_id()
             );
             return;
         }
+        None => None,
     };
 
-    if let Err(err) = monitor_prompt(config.get_prompt_path(), prompt_sender) {
-        tracing::error!(
-            "Unable to watch prompt file at '{}' for channel '{}'. The channel will be active, but the prompt wont be updated unless the program is restarted.",
-            config.get_prompt_path().display(),
-            config.get_channel_id()
-        );
-        tracing::error!("{err}");
+    let llm_http = match config.extra_headers().and_then(|headers| {
+        reqwest::Client::builder()
+            .default_headers(headers)
+            .build()
+            .context("Failed to create the http client")
+    }) {
+        Ok(llm_http) => llm_http,
+        Err(err) => {
+            error!("Unable to create the LLM api client: {err:?}");
+            error!(
+                "Channel with id '{}' will not be activated",
+                config.get_channel_id()
+            );
+            return;
+        }
+    };
+    let llm_client = match config.api_backend {
+        ApiBackend::OpenAI => {
+            let mut llm_config = OpenAIConfig::new().with_api_key(&config.llm_api_key);
+            if let Some(api_base) = &config.llm_api_base {
+                llm_config = llm_config.with_api_base(api_base);
+            }
+            LlmClient::OpenAI(
+                AIClient::with_config(llm_config)
+                    .with_http_client(llm_http)
+                    .with_backoff(
+                        backoff::ExponentialBackoffBuilder::new()
+                            .with_max_elapsed_time(Some(Duration::from_secs(
+                                config.backoff_max_elapsed_secs,
+                            )))
+                            .build(),
+                    ),
+            )
+        }
+        ApiBackend::Anthropic => LlmClient::Anthropic(anthropic::Client::new(
+            llm_http,
+            &config.llm_api_key,
+            config.llm_api_base.as_deref(),
+            Duration::from_secs(config.backoff_max_elapsed_secs),
+        )),
     };
 
-    let mut llm_config = OpenAIConfig::new().with_api_key(&config.llm_api_key);
-    if let Some(api_base) = &config.llm_api_base {
-        llm_config = llm_config.with_api_base(api_base);
-    }
-    let llm_client = AIClient::with_config(llm_config).with_backoff(
-        backoff::ExponentialBackoffBuilder::new()
-            .with_max_elapsed_time(Some(Duration::from_secs(5)))
-            .build(),
-    );
+    // Validation only allows image generation with the openai backend.
+    let image_generator = match (&config.image_generation, &llm_client) {
+        (Some(image_config), LlmClient::OpenAI(client)) => Some((image_config, client)),
+        _ => None,
+    };
 
     let max_history_size = config.max_history_size as usize;
-    let (message_tx, mut message_rx) = mpsc::channel(max_history_size / 2);
+    let (message_tx, mut message_rx) = mpsc::channel(config.queue_capacity);
+
+    let access = Access {
+        allowed_roles: config.allowed_roles.clone(),
+        allowed_users: config.allowed_users.clone(),
+        blocked_users: config.blocked_users.clone(),
+        min_account_age: config.min_account_age_secs.map(Duration::from_secs),
+        min_membership: config.min_membership_secs.map(Duration::from_secs),
+    };
+
+    let images = match ImageDownloader::new(
+        http.clone(),
+        Duration::from_secs(config.image_download_timeout_secs),
+        config.max_concurrent_image_downloads,
+    ) {
+        Ok(images) => images,
+        Err(err) => {
+            error!("{err:?}");
+            error!(
+                "Channel with id '{}' will not be activated",
+                config.get_channel_id()
+            );
+            return;
+        }
+    };
+
+    // Every thread has its own conversation, separate from the conversation in the main channel.
+    let mut conversations: HashMap<Id<ChannelMarker>, Conversation> = HashMap::new();
+    // The pseudonyms are shared by all conversations, so users keep their pseudonym in threads.
+    let mut pseudonyms =
+        (config.anonymize_ids || !config.include_usernames).then(Pseudonyms::default);
+
+    if config.seed_history {
+        let conversation = conversations.entry(config.channel_id).or_default();
+        match seed_history(
+            &config,
+            &http,
+            &cache,
+            &access,
+            &images,
+            pseudonyms.as_mut(),
+            conversation,
+        )
+        .await
+        {
+            Ok(()) => debug!(
+                "Seeded the history of {} with {} message(s)",
+                config.channel_id,
+                conversation.history.len()
+            ),
+            Err(err) => warn!(
+                "Failed to seed the history of {}, starting without history: {err:?}",
+                config.channel_id
+            ),
+        }
+    }
 
     // Spawn a task to handle incoming message events and queue them in the channel above.
-    tokio::spawn(queue_messages(events, message_tx, config.channel_id));
+    tokio::spawn(
+        queue_messages(
+            events,
+            message_tx,
+            config.clone(),
+            http.clone(),
+            cache,
+            access,
+            shared.clone(),
+        )
+        .in_current_span(),
+    );
 
-    let mut last_response_time = Instant::now();
-    let mut last_error_response = None;
-    let mut history = VecDeque::new();
+    // The metric handles are created once, recording to them does nothing when metrics are disabled.
+    let channel_label = [("channel_id", config.channel_id.to_string())];
+    let response_time_histogram = histogram!("bot_llm_response_seconds", &channel_label);
+    let error_counter = counter!("bot_llm_errors_total", &channel_label);
+    let empty_counter = counter!("bot_llm_empty_responses_total", &channel_label);
 
+    let mut usage_log = UsageLog::new(config.channel_id, config.usage_log_interval);
+
+    let mut response_delay = AdaptiveDelay::new(
+        Duration::from_millis(config.request_interval_ms),
+        Duration::from_millis(config.min_response_delay()),
+        Duration::from_millis(config.max_response_delay()),
+    );
+    let mut next_request_time = Instant::now();
     // Batch new messages together to avoid generating a separate response to each one.
     let mut new_messages = Vec::new();
     loop {
-        // Wait to avoid getting rate limited by the LLM endpoint.
-        // TODO: this could be handled better.
-        sleep_until(last_response_time + Duration::from_millis(1500)).await;
-
-        let recv_amt = message_rx
-            .recv_many(&mut new_messages, max_history_size)
-            .await;
+        // Responses that are being generated are finished and sent before shutting down, new
+        // messages are no longer received once the bot is shutting down.
+        let recv_amt = select! {
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            recv_amt = async {
+                // Wait to avoid getting rate limited by the LLM endpoint.
+                sleep_until(next_request_time).await;
+                message_rx.recv_many(&mut new_messages, max_history_size).await
+            } => recv_amt,
+        };
 
         if recv_amt == 0 {
             // The message ingestion channel has closed, gracefully shut down this task.
             break;
         }
 
-        let current_prompt =
-            ChatCompletionRequestMessage::System(prompt_receiver.borrow().as_ref().into());
+        // Group the new messages per conversation, keeping the order in which the conversations
+        // received their first new message.
+        let mut batches: Vec<(Id<ChannelMarker>, Vec<UserMessage>)> = Vec::new();
+        // The responses to edit, by the id of the edited message they responded to.
+        let mut edited_replies = HashMap::new();
+        // The messages asking for an image and the description of the image.
+        let mut image_requests = Vec::new();
+        for item in new_messages.drain(..) {
+            let msg = match item {
+                Queued::Message(msg) => {
+                    // The command is not added to the history.
+                    if let Some((image_config, _)) = image_generator
+                        && let Some((command, prompt)) =
+                            commands::parse_command(&msg.content, &shared.command_prefix)
+                        && command == image_config.command
+                    {
+                        let prompt = prompt.to_string();
+                        image_requests.push((msg, prompt));
+                        continue;
+                    }
+                    msg
+                }
+                // Edits are only queued when `edit_reply_on_edit` is set.
+                Queued::Edit(msg) => {
+                    let reply_id = conversations
+                        .get(&msg.channel_id)
+                        .and_then(|conversation| conversation.reply_to(msg.message_id));
+                    let Some(reply_id) = reply_id else {
+                        debug!(
+                            "Ignoring the edit of {}, there is no recent reply",
+                            msg.message_id
+                        );
+                        continue;
+                    };
+                    edited_replies.insert(msg.message_id, reply_id);
 
-        for msg in &new_messages {
-            let msg =
-                ChatCompletionRequestMessage::User(msg.as_chat_completion_message(&config).await);
+                    // The edit is added as a new message, so the LLM sees both versions.
+                    match batches.iter_mut().find(|(id, _)| *id == msg.channel_id) {
+                        Some((_, batch)) => batch.push(msg),
+                        None => batches.push((msg.channel_id, vec![msg])),
+                    }
+                    continue;
+                }
+                Queued::Reset(channel_id) => {
+                    // Messages sent before the reset are not part of the new conversation.
+                    batches.retain(|(id, _)| *id != channel_id);
+                    if let Some(conversation) = conversations.remove(&channel_id)
+                        && let Some(msg_id) = conversation.last_error_response
+                    {
+                        _ = http.delete_message(channel_id, msg_id).await;
+                    }
+                    debug!("Reset the conversation in {channel_id}");
+                    continue;
+                }
+            };
 
-            history.push_back(msg);
-        }
-        new_messages.clear();
-
-        if history.len() > max_history_size {
-            // Downsize the history buffer by removing some elements from the front until it is back
-            // to `min_history_size`. This is to ensure all messages fit in the context window while
-            // allowing the LLM cache to be re-used for the next messages.
-            let remove_from_front = history
-                .len()
-                .saturating_sub(config.min_history_size as usize);
-            // TODO: count history in tokens rather amount of messages.
-            history.drain(0..remove_from_front);
-
-            debug!("Downsized history to {}", history.len());
+            let Some((_, batch)) = batches.iter_mut().find(|(id, _)| *id == msg.channel_id) else {
+                batches.push((msg.channel_id, vec![msg]));
+                continue;
+            };
+            // Short messages sent right after each other take fewer tokens as a single message.
+            let msg = match (batch.last_mut(), config.merge_messages_within_secs) {
+                (Some(last), Some(window)) => match last.merge(msg, Duration::from_secs(window)) {
+                    Ok(()) => continue,
+                    Err(msg) => msg,
+                },
+                _ => msg,
+            };
+            batch.push(msg);
         }
 
-        let messages: Vec<_> = [current_prompt]
-            .into_iter()
-            .chain(history.iter().cloned())
-            .collect();
-
-        let response = generate_response(&llm_client, &config.model_name, messages).await;
-        last_response_time = Instant::now();
-
-        // Delete the previous error message. This should happen both if there is a new error
-        // message or there is another error.
-        if let Some(prev_err_msg_id) = last_error_response {
-            let http2 = http.clone();
-            tokio::spawn(async move {
-                if let Err(err) = http2
-                    .delete_message(config.channel_id, prev_err_msg_id)
+        // Images are generated before the responses, with the same pacing as requests to the LLM.
+        for (msg, prompt) in image_requests {
+            let Some((image_config, client)) = image_generator else {
+                break;
+            };
+
+            sleep_until(next_request_time).await;
+
+            if paused.load(Ordering::Relaxed) {
+                debug!(
+                    "Not generating the image requested by {}, the AI channels are paused",
+                    msg.message_id
+                );
+                continue;
+            }
+
+            if config.dry_run {
+                info!(
+                    "[dry-run] Image requested by message {}: {prompt}",
+                    msg.message_id
+                );
+                if let Err(err) = http
+                    .create_message(msg.channel_id)
+                    .content("[dry-run] would generate an image here")
                     .await
                 {
-                    error!("Failed to delete previous error message: {err}");
+                    error!("Failed to send the dry-run message: {err}");
                 }
+                continue;
+            }
+
+            image_generation::respond(
+                image_config,
+                client,
+                images.client(),
+                &http,
+                &shared.errors,
+                &msg,
+                &prompt,
+            )
+            .await;
+            next_request_time =
+                Instant::now() + with_jitter(response_delay.update(!message_rx.is_empty()));
+        }
+
+        for (channel_id, mut batch) in batches {
+            // Threads are responded to one after another, so the rate limit applies between them
+            // as well.
+            sleep_until(next_request_time).await;
+
+            // Checked after waiting, as the AI channels may have been paused in the meantime.
+            // Nothing is sent to the LLM while paused, images in the messages aren't downloaded
+            // either.
+            if paused.load(Ordering::Relaxed) {
+                debug!(
+                    "Not responding to {} message(s) in {channel_id}, the AI channels are paused",
+                    batch.len()
+                );
+                continue;
+            }
+
+            let conversation = conversations.entry(channel_id).or_default();
+            conversation.last_active = Instant::now();
+
+            // All messages in a conversation are sent in the same guild.
+            let overrides = batch
+                .first()
+                .and_then(|msg| msg.guild_id)
+                .and_then(|guild_id| shared.guild_overrides.get(&guild_id));
+            let model_name = overrides
+                .and_then(|overrides| overrides.model_name.as_deref())
+                .unwrap_or(&config.model_name);
+
+            // A prompt for the role of the most recent author takes precedence over the prompt of
+            // the guild.
+            let role_prompt_path = batch.last().and_then(|msg| {
+                config
+                    .role_prompts
+                    .iter()
+                    .find(|role_prompt| msg.sender_roles.contains(&role_prompt.role_id))
+                    .map(|role_prompt| role_prompt.prompt_path.as_ref())
             });
+            let prompt_path =
+                role_prompt_path.or_else(|| overrides.and_then(|o| o.prompt_path.as_deref()));
+
+            let override_prompt = match prompt_path {
+                Some(path) => {
+                    if !override_prompts.contains_key(path) {
+                        let prompt =
+                            load_and_monitor_prompt(&[path], config.max_prompt_bytes, channel_id)
+                                .await;
+                        override_prompts.insert(path.into(), prompt);
+                    }
+                    // Fall back to the channel prompt when the override prompt can't be read.
+                    override_prompts[path].as_ref()
+                }
+                None => None,
+            };
+            let mut system_prompt = override_prompt
+                .unwrap_or(&prompt_receiver)
+                .borrow()
+                .to_string();
+            let mut developer_message = None;
+            if let Some(developer_prompt) = &developer_prompt {
+                let developer_prompt = developer_prompt.borrow();
+                if config.developer_role() {
+                    developer_message = Some(ChatCompletionRequestMessage::Developer(
+                        ChatCompletionRequestDeveloperMessage {
+                            content: ChatCompletionRequestDeveloperMessageContent::Text(
+                                developer_prompt.to_string(),
+                            ),
+                            name: None,
+                        },
+                    ));
+                } else {
+                    system_prompt.push_str(PROMPT_SEPARATOR);
+                    system_prompt.push_str(&developer_prompt);
+                }
+            }
+            let current_prompt =
+                ChatCompletionRequestMessage::System(system_prompt.as_str().into());
+
+            for msg in &mut batch {
+                if let Some(pseudonyms) = &mut pseudonyms {
+                    pseudonyms.anonymize(msg);
+                }
+                conversation.add_author(msg, max_history_size);
+
+                let msg = ChatCompletionRequestMessage::User(
+                    msg.as_chat_completion_message(&config, &images).await,
+                );
+
+                conversation.history.push_back(msg);
+            }
+
+            let history = &mut conversation.history;
+            if trim_history(history, max_history_size, config.min_history_size as usize) {
+                debug!("Downsized history of {channel_id} to {}", history.len());
+            }
+
+            let messages: Vec<_> = [current_prompt]
+                .into_iter()
+                .chain(developer_message)
+                .chain(history.iter().cloned())
+                .collect();
+
+            if config.dry_run {
+                match serde_json::to_string_pretty(&messages) {
+                    Ok(messages) => info!("[dry-run] The messages sent to the LLM:\n{messages}"),
+                    Err(err) => error!("[dry-run] Failed to serialize the messages: {err}"),
+                }
+                if let Err(err) = http
+                    .create_message(channel_id)
+                    .content("[dry-run] would respond here")
+                    .await
+                {
+                    error!("Failed to send the dry-run message: {err}");
+                }
+                continue;
+            }
+
+            let may_retry = config.retry_on_empty_if_mentioned
+                && batch.last().is_some_and(|msg| msg.mentions_bot);
+            // The most recent message the response is for.
+            let trigger_id = batch.last().map(|msg| msg.message_id);
+
+            // The previous response to the message, when it was edited.
+            let edit_target = trigger_id.and_then(|id| edited_replies.get(&id).copied());
+
+            let placeholder = match &config.placeholder_message {
+                Some(placeholder) if edit_target.is_none() => {
+                    let reply_to = trigger_id.filter(|_| config.reply_to_trigger);
+                    send_placeholder(&http, channel_id, placeholder, reply_to).await
+                }
+                _ => None,
+            };
+
+            let request_start = Instant::now();
+            let response = generate_response(
+                &llm_client,
+                model_name,
+                messages,
+                &config.tools,
+                ResponseOptions {
+                    retry_empty: may_retry,
+                    strip_tags: &config.strip_tags,
+                    truncation_indicator: &config.truncation_indicator,
+                    response_format: config.response_format,
+                    stop: &config.stop,
+                    empty_counter: &empty_counter,
+                },
+                &mut usage_log,
+            )
+            .await;
+            let response_time = Instant::now();
+            response_time_histogram.record((response_time - request_start).as_secs_f64());
+
+            // Responses come quicker while the channel is quiet and are batched more when more
+            // messages are already waiting.
+            let request_interval = response_delay.update(!message_rx.is_empty());
+            // The wait is jittered so channels using the same API don't send requests in lockstep,
+            // but it is never shorter than the delay the API asked for.
+            next_request_time = match response.as_ref().err().and_then(retry_after) {
+                Some(delay) => {
+                    warn!("Rate limited by the LLM api, waiting {delay:?} before the next request");
+                    response_time + with_jitter(delay.max(request_interval)).max(delay)
+                }
+                None => response_time + with_jitter(request_interval),
+            };
+
+            // Delete the previous error message. This should happen both if there is a new error
+            // message or there is another error.
+            if let Some(prev_err_msg_id) = conversation.last_error_response.take() {
+                let http2 = http.clone();
+                tokio::spawn(
+                    async move {
+                        if let Err(err) = http2.delete_message(channel_id, prev_err_msg_id).await {
+                            error!("Failed to delete previous error message: {err}");
+                        }
+                    }
+                    .in_current_span(),
+                );
+            }
+
+            let mut response_content = match response {
+                Ok(Some(content)) => content,
+                Ok(None) => {
+                    debug!("Model chose to not respond");
+                    delete_placeholder(&http, channel_id, placeholder).await;
+                    continue;
+                }
+                Err(err) => {
+                    error!("Error creating response: {err:?}");
+                    error_counter.increment(1);
+                    delete_placeholder(&http, channel_id, placeholder).await;
+
+                    // Log the error in the channel.
+                    let err_msg = shared
+                        .errors
+                        .report(
+                            &http,
+                            "AI channel",
+                            channel_id,
+                            &format!(
+                                "Something went wrong while generating a response\n```\n{err}\n```"
+                            ),
+                        )
+                        .await;
+
+                    if let Some(err_msg) = err_msg {
+                        conversation.last_error_response = Some(err_msg.id);
+                    };
+                    continue;
+                }
+            };
+
+            // JSON responses are sent as is, rewriting their contents could make them invalid.
+            let is_json = config.response_format == ResponseFormat::JsonObject;
+
+            // The LLM sometimes copies the format of the user messages, which shouldn't be sent.
+            let sanitized = sanitize::strip_format_leakage(&response_content);
+            if !is_json && sanitized != response_content.trim() {
+                debug!("Removed the message format from the response");
+                if sanitized.is_empty() {
+                    delete_placeholder(&http, channel_id, placeholder).await;
+                    continue;
+                }
+                response_content = sanitized.to_string();
+            }
+
+            // Filter the response before it is added to the history, so blocked words don't end up
+            // in future responses either. JSON responses are never censored, they are not sent
+            // instead.
+            if let Some(content_filter) = &content_filter {
+                match content_filter.apply(&response_content) {
+                    Some(filtered) if !is_json || *filtered == *response_content => {
+                        response_content = filtered.into_owned();
+                    }
+                    _ => {
+                        warn!("Response contained blocked words and was not sent");
+                        delete_placeholder(&http, channel_id, placeholder).await;
+                        continue;
+                    }
+                }
+            }
+
+            let mut history_content = None;
+            if config.convert_mentions && !is_json {
+                let recent_authors = &conversation.recent_authors;
+                if let Some(pseudonyms) = &pseudonyms {
+                    // The LLM only knows the users by their pseudonyms, so the history keeps them.
+                    history_content = Some(response_content.clone());
+                    response_content = pseudonyms
+                        .convert_pseudonyms(&response_content, |user_id| {
+                            recent_authors.iter().any(|author| author.id == user_id)
+                        });
+                }
+                response_content = mentions::convert_mentions(&response_content, recent_authors);
+                // Mentions are longer than the names they replace, which can make the response too
+                // long for discord again.
+                response_content =
+                    truncate_response(response_content, false, &config.truncation_indicator);
+            }
+
+            conversation
+                .history
+                .push_back(ChatCompletionRequestMessage::Assistant(
+                    history_content
+                        .as_deref()
+                        .unwrap_or(&response_content)
+                        .into(),
+                ));
+
+            // Only allow pinging users that are part of the conversation, to prevent the LLM from
+            // being abused to ping lots of people. Everyone and role pings are disabled by default
+            // for the same reason.
+            let allowed_mentions = mentions::allowed_mentions(
+                &conversation.recent_authors,
+                config.allow_everyone_mentions,
+                config.allow_role_mentions,
+            );
+            // The previous response or the placeholder is edited into the response.
+            let mut sent_id = None;
+            if let Some(message_id) = edit_target.or(placeholder) {
+                match http
+                    .update_message(channel_id, message_id)
+                    .content(Some(&response_content))
+                    .allowed_mentions(Some(&allowed_mentions))
+                    .await
+                {
+                    Ok(_) => sent_id = Some(message_id),
+                    Err(err) => {
+                        warn!(
+                            "Failed to edit message {message_id}, sending a new message instead: \
+                             {err}"
+                        );
+                        delete_placeholder(&http, channel_id, placeholder).await;
+                    }
+                }
+            }
 
-            last_error_response = None;
+            if sent_id.is_none() {
+                let mut create_message = http
+                    .create_message(channel_id)
+                    .content(&response_content)
+                    .allowed_mentions(Some(&allowed_mentions));
+                if config.reply_to_trigger
+                    && let Some(trigger_id) = trigger_id
+                {
+                    // Sent as a normal message instead when the message was deleted in the
+                    // meantime.
+                    create_message = create_message.reply(trigger_id).fail_if_not_exists(false);
+                }
+                match create_message.await {
+                    Ok(response) => sent_id = response.model().await.ok().map(|msg| msg.id),
+                    Err(err) => {
+                        error!("Failed to send response message: {err}");
+                        continue;
+                    }
+                }
+            }
+
+            if let (Some(trigger_id), Some(sent_id)) = (trigger_id, sent_id) {
+                conversation.add_reply(trigger_id, sent_id);
+            }
         }
 
-        let mut response_content = match response {
-            Ok(v) => v,
-            Err(err) => {
-                error!("Error creating response: {err:?}");
-
-                // Log the error in the channel.
-                let err_msg = send_error_msg(
-                    &http,
-                    config.channel_id,
-                    &format!("Something went wrong while generating a response\n```\n{err}\n```"),
-                )
-                .await;
-
-                if let Some(err_msg) = err_msg {
-                    last_error_response = Some(err_msg.id);
+        conversations.retain(|channel_id, conversation| {
+            *channel_id == config.channel_id
+                || conversation.last_active.elapsed() < IDLE_THREAD_TIMEOUT
+        });
+    }
+
+    // Don't clutter the channel with lots of error messages.
+    for (channel_id, conversation) in conversations {
+        if let Some(msg_id) = conversation.last_error_response {
+            _ = http.delete_message(channel_id, msg_id).await;
+        }
+    }
+}
+
+/// Sends the placeholder shown while the response is generated and returns its id.
+async fn send_placeholder(
+    http: &Client,
+    channel_id: Id<ChannelMarker>,
+    content: &str,
+    reply_to: Option<Id<MessageMarker>>,
+) -> Option<Id<MessageMarker>> {
+    let mut create_message = http
+        .create_message(channel_id)
+        .content(content)
+        .allowed_mentions(Some(&AllowedMentions::default()));
+    if let Some(reply_to) = reply_to {
+        create_message = create_message.reply(reply_to).fail_if_not_exists(false);
+    }
+
+    let message = match create_message.await {
+        Ok(response) => response.model().await,
+        Err(err) => {
+            error!("Failed to send the placeholder message: {err}");
+            return None;
+        }
+    };
+    match message {
+        Ok(message) => Some(message.id),
+        Err(err) => {
+            error!("Failed to deserialize the placeholder message: {err}");
+            None
+        }
+    }
+}
+
+/// Deletes the placeholder, if one was sent, when there is no response to replace it with.
+async fn delete_placeholder(
+    http: &Client,
+    channel_id: Id<ChannelMarker>,
+    placeholder: Option<Id<MessageMarker>>,
+) {
+    if let Some(placeholder_id) = placeholder
+        && let Err(err) = http.delete_message(channel_id, placeholder_id).await
+    {
+        error!("Failed to delete the placeholder message: {err}");
+    }
+}
+
+/// The maximum amount of characters discord allows in a message.
+const MAX_MESSAGE_CHARS: usize = 2000;
+
+/// The maximum amount of stop sequences the OpenAI API allows.
+const MAX_STOP_SEQUENCES: usize = 4;
+
+/// The maximum length of the `truncation_indicator`, so most of the response is still sent.
+const MAX_TRUNCATION_INDICATOR_CHARS: usize = 100;
+
+/// Downsizes the history by removing messages from the front until it is back to `min_size`, once
+/// it is longer than `max_size`. Returns whether messages were removed.
+///
+/// This is to ensure all messages fit in the context window while allowing the LLM cache to be
+/// re-used for the next messages.
+fn trim_history<T>(history: &mut VecDeque<T>, max_size: usize, min_size: usize) -> bool {
+    if history.len() <= max_size {
+        return false;
+    }
+
+    // TODO: count history in tokens rather amount of messages.
+    let remove_from_front = history.len().saturating_sub(min_size);
+    history.drain(0..remove_from_front);
+    true
+}
+
+/// Returns the first `max` characters of `s`.
+///
+/// Characters are counted as unicode scalar values, so the string is never cut in the middle of a
+/// multibyte character.
+fn truncate_to_chars(s: &str, max: usize) -> &str {
+    match s.char_indices().nth(max) {
+        Some((end, _)) => &s[..end],
+        None => s,
+    }
+}
+
+/// Shortens the response to fit in a discord message.
+///
+/// When content is removed, or the LLM was already cut off before finishing the response, the
+/// `indicator` is appended so users know they're not seeing the whole response.
+fn truncate_response(mut content: String, cut_off: bool, indicator: &str) -> String {
+    if !cut_off && content.chars().count() <= MAX_MESSAGE_CHARS {
+        return content;
+    }
+
+    let keep = MAX_MESSAGE_CHARS - indicator.chars().count();
+    content.truncate(truncate_to_chars(&content, keep).len());
+    content.push_str(indicator);
+    content
+}
+
+/// Adds the most recent messages in the AI channel to the conversation, so the AI knows what was
+/// being talked about before it started.
+///
+/// Messages are filtered the same way as new messages, except that the messages of the bot itself
+/// are added as its responses.
+async fn seed_history(
+    config: &Configuration,
+    http: &Client,
+    cache: &InMemoryCache,
+    access: &Access,
+    images: &ImageDownloader,
+    mut pseudonyms: Option<&mut Pseudonyms>,
+    conversation: &mut Conversation,
+) -> anyhow::Result<()> {
+    let bot_id = http.current_user().await?.model().await?.id;
+
+    // Discord returns at most 100 messages, the newest message first.
+    let limit = config.max_history_size.min(100) as u16;
+    let messages = http
+        .channel_messages(config.channel_id)
+        .limit(limit)
+        .await?
+        .models()
+        .await?;
+
+    for message in messages.iter().rev() {
+        if message.author.id == bot_id {
+            // Error messages are embeds without content.
+            if !message.content.is_empty() {
+                let content = match pseudonyms.as_deref_mut() {
+                    Some(pseudonyms) => pseudonyms.anonymize_mentions(&message.content),
+                    None => message.content.clone(),
                 };
-                continue;
+                conversation
+                    .history
+                    .push_back(ChatCompletionRequestMessage::Assistant(
+                        content.as_str().into(),
+                    ));
             }
-        };
-        // Take only the first 2000 characters to stay within the discord character limit.
-        response_content.truncate(
-            response_content
-                .char_indices()
-                .take(2000)
-                .map(|v| v.0 + v.1.len_utf8())
-                .last()
-                .unwrap_or(0),
-        );
-
-        if response_content.contains("<empty/>") {
-            debug!("Model chose to not respond");
             continue;
         }
 
-        history.push_back(ChatCompletionRequestMessage::Assistant(
-            response_content.as_str().into(),
-        ));
-
-        if let Err(err) = http
-            .create_message(config.channel_id)
-            .content(&response_content)
-            .await
-        {
-            error!("Failed to send response message: {err}");
+        let sender_roles = sender_roles(message, cache);
+        if message.author.bot || !access.is_allowed(message.author.id, &sender_roles) {
             continue;
         }
+
+        let mut msg = UserMessage::new(message, sender_roles, mentions_user(message, bot_id));
+        if let Some(pseudonyms) = pseudonyms.as_deref_mut() {
+            pseudonyms.anonymize(&mut msg);
+        }
+        conversation.add_author(&msg, config.max_history_size as usize);
+        conversation
+            .history
+            .push_back(ChatCompletionRequestMessage::User(
+                msg.as_chat_completion_message(config, images).await,
+            ));
     }
 
-    // Don't clutter the channel with lots of error messages.
-    if let Some(msg_id) = last_error_response {
-        _ = http.delete_message(config.channel_id, msg_id).await;
+    Ok(())
+}
+
+/// Reads the channel prompt and watches it for changes.
+///
+/// Errors are logged, `None` is returned when the prompt could not be read.
+async fn load_and_monitor_prompt(
+    prompt_paths: &[&Path],
+    max_bytes: u64,
+    channel_id: Id<ChannelMarker>,
+) -> Option<watch::Receiver<Box<str>>> {
+    let (prompt_sender, prompt_receiver) = match load_prompts(prompt_paths, max_bytes).await {
+        Ok(var) => var,
+        Err(err) => {
+            tracing::error!("Unable to read channel prompt: {err:?}");
+            return None;
+        }
+    };
+
+    if let Err(err) = monitor_prompts(prompt_paths, prompt_sender, max_bytes) {
+        tracing::error!(
+            "Unable to watch the prompt files for channel '{}'. The channel will be active, but the prompt wont be updated unless the program is restarted.",
+            channel_id
+        );
+        tracing::error!("{err}");
+    };
+
+    Some(prompt_receiver)
+}
+
+/// The state of a single conversation, this is either the AI channel itself or one of its threads.
+#[derive(Debug)]
+struct Conversation {
+    history: VecDeque<ChatCompletionRequestMessage>,
+    last_error_response: Option<Id<MessageMarker>>,
+    /// The authors of the recent messages, the most recent author is last.
+    recent_authors: VecDeque<Author>,
+    /// The recent responses of the bot, the most recent response is last.
+    replies: VecDeque<Reply>,
+    /// When the last messages were added to the conversation.
+    last_active: Instant,
+}
+
+impl Default for Conversation {
+    fn default() -> Self {
+        Self {
+            history: VecDeque::new(),
+            last_error_response: None,
+            recent_authors: VecDeque::new(),
+            replies: VecDeque::new(),
+            last_active: Instant::now(),
+        }
     }
 }
 
+/// A response of the bot, kept to edit it when the message it responded to is edited.
+#[derive(Debug)]
+struct Reply {
+    /// The most recent message the response responded to.
+    trigger_id: Id<MessageMarker>,
+    reply_id: Id<MessageMarker>,
+    sent_at: Instant,
+}
+
+/// The maximum amount of responses per conversation that are edited when their message is.
+const MAX_TRACKED_REPLIES: usize = 20;
+
+/// Responses older than this are not edited anymore, so old conversations aren't answered again.
+const EDIT_WINDOW: Duration = Duration::from_secs(10 * 60);
+
+/// Threads without new messages for this long forget their conversation, so the conversations of
+/// every thread the bot ever responded in don't stay in memory.
+const IDLE_THREAD_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl Conversation {
+    /// Marks the sender of the message as the most recent author, keeping at most `max_authors`
+    /// authors.
+    fn add_author(&mut self, msg: &UserMessage, max_authors: usize) {
+        self.recent_authors
+            .retain(|author| author.id != msg.sender_id);
+        self.recent_authors.push_back(Author {
+            id: msg.sender_id,
+            name: msg.sender_name.clone(),
+            display_name: msg.sender_display_name.clone(),
+        });
+
+        while self.recent_authors.len() > max_authors {
+            self.recent_authors.pop_front();
+        }
+    }
+
+    /// Remembers the response to the message with `trigger_id`.
+    fn add_reply(&mut self, trigger_id: Id<MessageMarker>, reply_id: Id<MessageMarker>) {
+        self.replies.retain(|reply| reply.trigger_id != trigger_id);
+        self.replies.push_back(Reply {
+            trigger_id,
+            reply_id,
+            sent_at: Instant::now(),
+        });
+
+        while self.replies.len() > MAX_TRACKED_REPLIES {
+            self.replies.pop_front();
+        }
+    }
+
+    /// The recent response to the message with `trigger_id`.
+    fn reply_to(&self, trigger_id: Id<MessageMarker>) -> Option<Id<MessageMarker>> {
+        self.replies
+            .iter()
+            .find(|reply| reply.trigger_id == trigger_id && reply.sent_at.elapsed() <= EDIT_WINDOW)
+            .map(|reply| reply.reply_id)
+    }
+}
+
+/// Added to the history when the LLM did not respond to a message that mentioned the bot.
+const EMPTY_RETRY_INSTRUCTION: &str =
+    "The last message mentioned you directly, reply to it instead of responding with <empty/>.";
+
 /// Sent by the model in response to a chat history.
 ///
 /// A custom type is used here as some (gemini *caugh caugh*) APIs dont return all fields.
 #[derive(Debug, Deserialize)]
 struct ChatCompletionResponse {
     choices: Vec<ChatChoice>,
+    usage: Option<Usage>,
+}
+
+/// A response generated by the LLM.
+struct Completion {
+    content: String,
+    /// Whether the LLM stopped because it reached the maximum amount of tokens.
+    cut_off: bool,
+    /// The tokens used by all requests needed to generate the response.
+    usage: Usage,
 }
 
-/// Send the chat history to the LLM api and generate a response based on this history.
+/// The maximum amount of times the LLM can call tools before responding.
+const MAX_TOOL_ROUNDS: usize = 5;
+
+/// The maximum amount of tokens the LLM may generate for a response.
+const MAX_TOKENS: u32 = 400;
+
+/// The client for the API used by an AI channel.
+enum LlmClient {
+    OpenAI(AIClient<OpenAIConfig>),
+    Anthropic(anthropic::Client),
+}
+
+/// How the response to a conversation is generated and cleaned up.
+struct ResponseOptions<'a> {
+    /// Ask the LLM once more to respond when it chose not to.
+    retry_empty: bool,
+    /// The contents of these tags are removed from the response.
+    strip_tags: &'a [(String, String)],
+    /// Appended to responses that were cut off.
+    truncation_indicator: &'a str,
+    response_format: ResponseFormat,
+    /// The LLM stops generating when it writes one of these.
+    stop: &'a [String],
+    /// Counts the responses in which the LLM chose not to respond, including those that are
+    /// retried.
+    empty_counter: &'a Counter,
+}
+
+/// Generates the response to the conversation in `messages`.
+///
+/// Returns `None` when the LLM chose not to respond with `<empty/>`, or when the response only
+/// consisted of stripped tags. When `retry_empty` is set, the LLM is asked once more to respond
+/// before giving up. The response is truncated to fit in a discord message, ending with the
+/// `truncation_indicator` when it was cut off.
+///
+/// JSON responses are never empty or truncated, `None` is returned instead when they are invalid
+/// or too long.
 async fn generate_response(
-    client: &AIClient<OpenAIConfig>,
+    completer: &impl ChatCompleter,
     model_name: &str,
-    history: Vec<ChatCompletionRequestMessage>,
-) -> anyhow::Result<String> {
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(model_name)
-        .max_tokens(400u32)
-        .messages(history)
-        .build()
-        .context("Failed to build request")?;
-
-    let response: ChatCompletionResponse = client
-        .chat()
-        .create_byot(request)
-        .await
-        .context("LLM api returned an error")?;
-
-    let response_content = match response.choices.first() {
-        Some(ChatChoice {
-            message:
-                ChatCompletionResponseMessage {
-                    content: Some(content),
-                    ..
-                },
-            ..
-        }) => content.as_str(),
-        _ => {
+    mut messages: Vec<ChatCompletionRequestMessage>,
+    tools: &[Tool],
+    options: ResponseOptions<'_>,
+    usage_log: &mut UsageLog,
+) -> anyhow::Result<Option<String>> {
+    let format = options.response_format;
+    let first_messages = if options.retry_empty {
+        messages.clone()
+    } else {
+        std::mem::take(&mut messages)
+    };
+    let mut completion = completer
+        .complete(model_name, first_messages, tools, format, options.stop)
+        .await?;
+    usage_log.record(completion.usage);
+    // Reasoning may mention `<empty/>` as well, so the tags are stripped before checking for it.
+    let mut content = sanitize::strip_tags(&completion.content, options.strip_tags);
+
+    if format == ResponseFormat::JsonObject {
+        return Ok(validate_json_response(content, completion.cut_off));
+    }
+
+    if options.retry_empty && content.contains("<empty/>") {
+        debug!("Empty response to a mention, retrying once");
+        options.empty_counter.increment(1);
+
+        messages.push(ChatCompletionRequestMessage::System(
+            EMPTY_RETRY_INSTRUCTION.into(),
+        ));
+        completion = completer
+            .complete(model_name, messages, tools, format, options.stop)
+            .await?;
+        usage_log.record(completion.usage);
+        content = sanitize::strip_tags(&completion.content, options.strip_tags);
+    }
+
+    if content.contains("<empty/>") {
+        debug!("The LLM responded with <empty/>");
+        options.empty_counter.increment(1);
+        return Ok(None);
+    }
+    if content.is_empty() {
+        debug!("The response only consisted of stripped tags");
+        return Ok(None);
+    }
+    // Continuing the response in a follow-up message is not supported, the indicator marks that
+    // the rest was dropped.
+    if completion.cut_off {
+        warn!("The response was cut off, the LLM reached the maximum of {MAX_TOKENS} tokens");
+    }
+    Ok(Some(truncate_response(
+        content,
+        completion.cut_off,
+        options.truncation_indicator,
+    )))
+}
+
+/// Returns the JSON response if it can be sent as is.
+///
+/// Cutting off JSON makes it invalid, so responses that are too long are not sent at all.
+fn validate_json_response(content: String, cut_off: bool) -> Option<String> {
+    if cut_off {
+        warn!("Not sending the JSON response, the LLM reached the maximum of {MAX_TOKENS} tokens");
+        return None;
+    }
+    if let Err(err) = serde_json::from_str::<serde_json::Value>(&content) {
+        warn!("Not sending the response, it is not valid JSON: {err}");
+        return None;
+    }
+    if content.chars().count() > MAX_MESSAGE_CHARS {
+        warn!("Not sending the JSON response, it is longer than {MAX_MESSAGE_CHARS} characters");
+        return None;
+    }
+    Some(content)
+}
+
+/// Generates a single completion for a chat history.
+///
+/// This is implemented by the API clients, and by a mock in tests.
+trait ChatCompleter {
+    async fn complete(
+        &self,
+        model_name: &str,
+        messages: Vec<ChatCompletionRequestMessage>,
+        tools: &[Tool],
+        response_format: ResponseFormat,
+        stop: &[String],
+    ) -> anyhow::Result<Completion>;
+}
+
+impl ChatCompleter for LlmClient {
+    /// Send the chat history to the LLM api and generate a response based on this history.
+    ///
+    /// When the LLM calls any of the `tools`, their results are added to the history and the LLM
+    /// is asked to respond again.
+    async fn complete(
+        &self,
+        model_name: &str,
+        mut history: Vec<ChatCompletionRequestMessage>,
+        tools: &[Tool],
+        response_format: ResponseFormat,
+        stop: &[String],
+    ) -> anyhow::Result<Completion> {
+        let client = match self {
+            LlmClient::OpenAI(client) => client,
+            LlmClient::Anthropic(client) => {
+                return client
+                    .generate_response(model_name, MAX_TOKENS, stop, &history)
+                    .await;
+            }
+        };
+
+        let mut usage = Usage::default();
+        for _ in 0..MAX_TOOL_ROUNDS {
+            let mut request = CreateChatCompletionRequestArgs::default();
+            request
+                .model(model_name)
+                .max_tokens(MAX_TOKENS)
+                .messages(history.clone());
+            if !stop.is_empty() {
+                request.stop(Stop::StringArray(stop.to_vec()));
+            }
+            if response_format == ResponseFormat::JsonObject {
+                request.response_format(async_openai::types::ResponseFormat::JsonObject);
+            }
+            if !tools.is_empty() {
+                request.tools(
+                    tools
+                        .iter()
+                        .map(|tool| tool.definition())
+                        .collect::<Vec<_>>(),
+                );
+            }
+            let request = request.build().context("Failed to build request")?;
+
+            let response: ChatCompletionResponse = client
+                .chat()
+                .create_byot(request)
+                .await
+                .context("LLM api returned an error")?;
+            usage += response.usage.unwrap_or_default();
+
+            anyhow::ensure!(
+                !response.choices.is_empty(),
+                "LLM response did not include a message"
+            );
+            let cut_off = response
+                .choices
+                .iter()
+                .any(|choice| choice.finish_reason == Some(FinishReason::Length));
+            let messages: Vec<ChatCompletionResponseMessage> = response
+                .choices
+                .into_iter()
+                .map(|choice| choice.message)
+                .collect();
+
+            // Some APIs don't put the response in the first choice, so every choice is checked. Tool
+            // calls are handled first, then the first choice with content is used.
+            let tool_calls = messages.iter().find_map(|message| {
+                message
+                    .tool_calls
+                    .clone()
+                    .filter(|tool_calls| !tool_calls.is_empty())
+            });
+            if let Some(tool_calls) = tool_calls {
+                history.push(ChatCompletionRequestMessage::Assistant(
+                    ChatCompletionRequestAssistantMessage {
+                        tool_calls: Some(tool_calls.clone()),
+                        ..Default::default()
+                    },
+                ));
+
+                for tool_call in tool_calls {
+                    let result = tools::call(
+                        tools,
+                        &tool_call.function.name,
+                        &tool_call.function.arguments,
+                    );
+                    debug!(
+                        "Tool {}({}) returned: {result}",
+                        tool_call.function.name, tool_call.function.arguments
+                    );
+
+                    history.push(ChatCompletionRequestMessage::Tool(
+                        ChatCompletionRequestToolMessage {
+                            content: ChatCompletionRequestToolMessageContent::Text(result),
+                            tool_call_id: tool_call.id,
+                        },
+                    ));
+                }
+                continue;
+            }
+
+            let content = messages.iter().find_map(|message| {
+                message
+                    .content
+                    .clone()
+                    .filter(|content| !content.trim().is_empty())
+            });
+            if let Some(content) = content {
+                return Ok(Completion {
+                    content,
+                    cut_off,
+                    usage,
+                });
+            }
+
+            // A refusal is sent as the response, so users know why the LLM didn't answer.
+            let refusal = messages.iter().find_map(|message| message.refusal.clone());
+            if let Some(refusal) = refusal {
+                warn!("LLM refused to respond: {refusal}");
+                return Ok(Completion {
+                    content: refusal,
+                    cut_off,
+                    usage,
+                });
+            }
+
             anyhow::bail!("LLM response did not include message content");
         }
-    };
 
-    Ok(response_content.to_string())
+        anyhow::bail!("LLM called tools more than {MAX_TOOL_ROUNDS} times without responding")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_exact_length_unchanged() {
+        let s = "a".repeat(2000);
+        assert_eq!(truncate_to_chars(&s, 2000), s);
+    }
+
+    #[test]
+    fn truncate_one_over_limit() {
+        let s = "a".repeat(2001);
+        assert_eq!(truncate_to_chars(&s, 2000), "a".repeat(2000));
+    }
+
+    /// Multibyte characters at the boundary must be kept or removed as a whole.
+    #[test]
+    fn truncate_multibyte_boundary() {
+        let s = format!("{}🦀文字", "a".repeat(1999));
+        assert_eq!(
+            truncate_to_chars(&s, 2000),
+            format!("{}🦀", "a".repeat(1999))
+        );
+        assert_eq!(
+            truncate_to_chars(&s, 2001),
+            format!("{}🦀文", "a".repeat(1999))
+        );
+        assert_eq!(truncate_to_chars("文字", 0), "");
+    }
+
+    #[test]
+    fn resize_filters_deserialized() {
+        let filter: ResizeFilter = serde_json::from_str(r#""catmullrom""#).unwrap();
+        assert_eq!(filter, ResizeFilter::CatmullRom);
+        let filter: ResizeFilter = serde_json::from_str(r#""lanczos3""#).unwrap();
+        assert_eq!(filter, ResizeFilter::Lanczos3);
+    }
+
+    #[test]
+    fn trim_history_to_min_size() {
+        let mut history: VecDeque<_> = (0..41).collect();
+        assert!(trim_history(&mut history, 40, 30));
+        assert_eq!(history, (11..41).collect::<VecDeque<_>>());
+
+        let mut history: VecDeque<_> = (0..40).collect();
+        assert!(!trim_history(&mut history, 40, 30));
+        assert_eq!(history.len(), 40);
+    }
+
+    #[test]
+    fn recent_replies_tracked() {
+        let mut conversation = Conversation::default();
+        for id in 1..=MAX_TRACKED_REPLIES as u64 + 1 {
+            conversation.add_reply(Id::new(id), Id::new(id + 1000));
+        }
+        assert_eq!(conversation.replies.len(), MAX_TRACKED_REPLIES);
+        assert_eq!(conversation.reply_to(Id::new(1)), None);
+        assert_eq!(conversation.reply_to(Id::new(2)), Some(Id::new(1002)));
+    }
+
+    /// Returns canned responses instead of calling an API.
+    #[derive(Default)]
+    struct MockCompleter {
+        responses: std::sync::Mutex<VecDeque<anyhow::Result<String>>>,
+        requests: std::sync::Mutex<Vec<Vec<ChatCompletionRequestMessage>>>,
+        /// Whether every response is marked as cut off by the token limit.
+        cut_off: bool,
+    }
+
+    impl MockCompleter {
+        fn new(responses: impl IntoIterator<Item = anyhow::Result<String>>) -> Self {
+            Self {
+                responses: std::sync::Mutex::new(responses.into_iter().collect()),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl ChatCompleter for MockCompleter {
+        async fn complete(
+            &self,
+            _model_name: &str,
+            messages: Vec<ChatCompletionRequestMessage>,
+            _tools: &[Tool],
+            _response_format: ResponseFormat,
+            _stop: &[String],
+        ) -> anyhow::Result<Completion> {
+            self.requests.lock().unwrap().push(messages);
+            let content = self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("no more mock responses")?;
+            Ok(Completion {
+                content,
+                cut_off: self.cut_off,
+                usage: Usage::default(),
+            })
+        }
+    }
+
+    async fn respond(
+        completer: &MockCompleter,
+        retry_empty: bool,
+    ) -> anyhow::Result<Option<String>> {
+        let messages = vec![ChatCompletionRequestMessage::User("Hello".into())];
+        let mut usage_log = UsageLog::new(Id::new(1), None);
+        generate_response(
+            completer,
+            "model",
+            messages,
+            &[],
+            ResponseOptions {
+                retry_empty,
+                strip_tags: &default_strip_tags(),
+                truncation_indicator: "…",
+                response_format: ResponseFormat::Text,
+                stop: &[],
+                empty_counter: &Counter::noop(),
+            },
+            &mut usage_log,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn response_is_returned() {
+        let completer = MockCompleter::new([Ok("Hi!".to_string())]);
+        assert_eq!(
+            respond(&completer, false).await.unwrap().as_deref(),
+            Some("Hi!")
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_response_is_not_sent() {
+        let completer = MockCompleter::new([Ok("<empty/>".to_string())]);
+        assert_eq!(respond(&completer, false).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn error_is_returned() {
+        let completer = MockCompleter::new([Err(anyhow::anyhow!("API unavailable"))]);
+        assert!(respond(&completer, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn long_response_is_truncated() {
+        let completer = MockCompleter::new([Ok("a".repeat(2500))]);
+        let content = respond(&completer, false).await.unwrap().unwrap();
+        assert_eq!(content.chars().count(), MAX_MESSAGE_CHARS);
+        assert!(content.ends_with("a…"));
+    }
+
+    #[tokio::test]
+    async fn cut_off_response_is_marked() {
+        let completer = MockCompleter {
+            cut_off: true,
+            ..MockCompleter::new([Ok("The answer is".to_string())])
+        };
+        assert_eq!(
+            respond(&completer, false).await.unwrap().as_deref(),
+            Some("The answer is…")
+        );
+    }
+
+    #[test]
+    fn only_valid_json_is_sent() {
+        assert_eq!(
+            validate_json_response(r#"{"answer": 42}"#.to_string(), false).as_deref(),
+            Some(r#"{"answer": 42}"#)
+        );
+        assert_eq!(validate_json_response("<empty/>".to_string(), false), None);
+        assert_eq!(
+            validate_json_response(r#"{"answer": 4"#.to_string(), true),
+            None
+        );
+
+        let too_long = format!(r#"{{"answer": "{}"}}"#, "a".repeat(MAX_MESSAGE_CHARS));
+        assert_eq!(validate_json_response(too_long, false), None);
+    }
+
+    #[test]
+    fn complete_response_is_not_marked() {
+        let content = "a".repeat(MAX_MESSAGE_CHARS);
+        assert_eq!(truncate_response(content.clone(), false, "…"), content);
+        assert_eq!(
+            truncate_response("a".repeat(2500), false, ""),
+            "a".repeat(MAX_MESSAGE_CHARS)
+        );
+    }
+
+    #[tokio::test]
+    async fn reasoning_is_stripped() {
+        let completer = MockCompleter::new([
+            Ok("<think>Should I answer?</think>Hi!".to_string()),
+            Ok("<think>I'll stay quiet.".to_string()),
+        ]);
+        assert_eq!(
+            respond(&completer, false).await.unwrap().as_deref(),
+            Some("Hi!")
+        );
+        assert_eq!(respond(&completer, false).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn empty_response_is_retried() {
+        let completer =
+            MockCompleter::new([Ok("<empty/>".to_string()), Ok("Hi again!".to_string())]);
+        assert_eq!(
+            respond(&completer, true).await.unwrap().as_deref(),
+            Some("Hi again!")
+        );
+
+        let requests = completer.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(
+            requests[1].last(),
+            Some(ChatCompletionRequestMessage::System(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn retry_stays_empty() {
+        let completer =
+            MockCompleter::new([Ok("<empty/>".to_string()), Ok("<empty/>".to_string())]);
+        assert_eq!(respond(&completer, true).await.unwrap(), None);
+    }
 }
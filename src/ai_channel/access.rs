@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use twilight_model::{
+    id::{
+        Id,
+        marker::{RoleMarker, UserMarker},
+    },
+    util::Timestamp,
+};
+
+/// The first millisecond of 2015, the time discord ids count from.
+const DISCORD_EPOCH_MS: i64 = 1_420_070_400_000;
+
+/// Decides which users are allowed to talk to the AI.
+#[derive(Debug, Default, Clone)]
+pub struct Access {
+    pub allowed_roles: Vec<Id<RoleMarker>>,
+    pub allowed_users: Vec<Id<UserMarker>>,
+    pub blocked_users: Vec<Id<UserMarker>>,
+    /// Accounts created less than this long ago are not allowed.
+    pub min_account_age: Option<Duration>,
+    /// Members that joined the guild less than this long ago are not allowed.
+    pub min_membership: Option<Duration>,
+}
+
+impl Access {
+    /// Whether the user with the given roles is allowed to talk to the AI.
+    ///
+    /// Blocked users are never allowed. When there are no allowed users or roles everyone else is
+    /// allowed, otherwise the user must be allowed or have an allowed role.
+    pub fn is_allowed(&self, user_id: Id<UserMarker>, roles: &[Id<RoleMarker>]) -> bool {
+        if self.blocked_users.contains(&user_id) {
+            return false;
+        }
+
+        if self.allowed_roles.is_empty() && self.allowed_users.is_empty() {
+            return true;
+        }
+
+        self.allowed_users.contains(&user_id)
+            || roles.iter().any(|role| self.allowed_roles.contains(role))
+    }
+
+    /// Whether the account of the user and their membership of the guild are old enough, at the
+    /// time `now`.
+    ///
+    /// The age of the account is read from the id of the user. When the time the user joined the
+    /// guild is unknown, for example in direct messages, only the age of the account is checked.
+    pub fn is_old_enough(
+        &self,
+        user_id: Id<UserMarker>,
+        joined_at: Option<Timestamp>,
+        now: Timestamp,
+    ) -> bool {
+        let now_ms = now.as_micros() / 1000;
+        let created_ms = (user_id.get() >> 22) as i64 + DISCORD_EPOCH_MS;
+        let account_ok = self
+            .min_account_age
+            .is_none_or(|min_age| now_ms - created_ms >= min_age.as_millis() as i64);
+        let membership_ok = match (self.min_membership, joined_at) {
+            (Some(min_membership), Some(joined_at)) => {
+                now_ms - joined_at.as_micros() / 1000 >= min_membership.as_millis() as i64
+            }
+            _ => true,
+        };
+        account_ok && membership_ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_lists_allow_everyone() {
+        assert!(Access::default().is_allowed(Id::new(1), &[]));
+    }
+
+    #[test]
+    fn blocked_user_denied() {
+        let access = Access {
+            allowed_users: vec![Id::new(1)],
+            blocked_users: vec![Id::new(1)],
+            ..Default::default()
+        };
+        assert!(!access.is_allowed(Id::new(1), &[]));
+    }
+
+    #[test]
+    fn allow_list_by_user_or_role() {
+        let access = Access {
+            allowed_roles: vec![Id::new(10)],
+            allowed_users: vec![Id::new(1)],
+            ..Default::default()
+        };
+        assert!(access.is_allowed(Id::new(1), &[]));
+        assert!(access.is_allowed(Id::new(2), &[Id::new(10)]));
+        assert!(!access.is_allowed(Id::new(3), &[Id::new(11)]));
+    }
+
+    #[test]
+    fn new_accounts_and_members_denied() {
+        let access = Access {
+            min_account_age: Some(Duration::from_secs(60 * 60)),
+            min_membership: Some(Duration::from_secs(10 * 60)),
+            ..Default::default()
+        };
+        // Created at 2015-01-01 01:00 UTC.
+        let user_id = Id::new((60 * 60 * 1000) << 22);
+        let joined_at = Timestamp::from_secs(1_420_074_000).unwrap();
+
+        let at = |secs| Timestamp::from_secs(1_420_074_000 + secs).unwrap();
+        assert!(!access.is_old_enough(user_id, Some(joined_at), at(30 * 60)));
+        assert!(access.is_old_enough(user_id, Some(joined_at), at(60 * 60)));
+        // The join time is only checked when it is known.
+        assert!(access.is_old_enough(user_id, None, at(60 * 60)));
+        assert!(Access::default().is_old_enough(user_id, None, at(0)));
+    }
+}
@@ -0,0 +1,436 @@
+use std::{fmt, time::Duration};
+
+use anyhow::Context;
+use async_openai::types::{
+    ChatCompletionRequestAssistantMessageContent, ChatCompletionRequestAssistantMessageContentPart,
+    ChatCompletionRequestDeveloperMessageContent, ChatCompletionRequestMessage,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestSystemMessageContentPart,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
+};
+use backoff::{ExponentialBackoffBuilder, backoff::Backoff};
+use reqwest::{
+    StatusCode,
+    header::{HeaderMap, RETRY_AFTER},
+};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{Completion, rate_limit::with_jitter, usage::Usage};
+
+const DEFAULT_API_BASE: &str = "https://api.anthropic.com/v1";
+const API_VERSION: &str = "2023-06-01";
+
+/// A client for the Anthropic messages API.
+///
+/// The chat history is kept in the OpenAI format and converted when sending a request, so the rest
+/// of the AI channel does not depend on the API used.
+#[derive(Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    api_key: String,
+    api_base: String,
+    /// How long requests that failed because of rate limits or server errors are retried.
+    max_retry_time: Duration,
+}
+
+impl Client {
+    /// Sends the requests using `http`, which can add headers of its own.
+    pub fn new(
+        http: reqwest::Client,
+        api_key: &str,
+        api_base: Option<&str>,
+        max_retry_time: Duration,
+    ) -> Self {
+        Self {
+            http,
+            api_key: api_key.to_string(),
+            api_base: api_base
+                .unwrap_or(DEFAULT_API_BASE)
+                .trim_end_matches('/')
+                .to_string(),
+            max_retry_time,
+        }
+    }
+
+    /// Send the chat history to the API and generate a response based on this history.
+    pub async fn generate_response(
+        &self,
+        model_name: &str,
+        max_tokens: u32,
+        stop: &[String],
+        history: &[ChatCompletionRequestMessage],
+    ) -> anyhow::Result<Completion> {
+        let mut request = build_request(model_name, max_tokens, history);
+        request.stop_sequences = stop;
+
+        let mut backoff = ExponentialBackoffBuilder::new()
+            .with_max_elapsed_time(Some(self.max_retry_time))
+            .build();
+        let response = loop {
+            let response = self
+                .http
+                .post(format!("{}/messages", self.api_base))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", API_VERSION)
+                .json(&request)
+                .send()
+                .await;
+
+            let retry_after = match &response {
+                Ok(response) if is_retryable(response.status()) => {
+                    retry_after_header(response.headers())
+                }
+                Err(err) if err.is_connect() || err.is_timeout() => None,
+                _ => break response,
+            };
+            let Some(delay) = backoff.next_backoff() else {
+                break response;
+            };
+            // Waiting longer than requests are retried is left to the pacing of the AI channel.
+            let delay = match retry_after {
+                Some(retry_after) if retry_after > self.max_retry_time => break response,
+                Some(retry_after) => with_jitter(delay.max(retry_after)).max(retry_after),
+                None => with_jitter(delay),
+            };
+
+            warn!("Anthropic api request failed, retrying in {delay:?}");
+            tokio::time::sleep(delay).await;
+        }
+        .context("Failed to send request to the Anthropic api")?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = if is_retryable(status) {
+                retry_after_header(response.headers())
+            } else {
+                None
+            };
+            let message = match response.json::<ErrorResponse>().await {
+                Ok(error) => format!("{}: {}", error.error.kind, error.error.message),
+                Err(_) => "unknown error".to_string(),
+            };
+            return Err(StatusError {
+                status,
+                message,
+                retry_after,
+            }
+            .into());
+        }
+
+        let response: MessagesResponse = response
+            .json()
+            .await
+            .context("Failed to deserialize the Anthropic api response")?;
+
+        let content: String = response
+            .content
+            .into_iter()
+            .filter_map(|block| match block {
+                ResponseBlock::Text { text } => Some(text),
+                ResponseBlock::Other => None,
+            })
+            .collect();
+        anyhow::ensure!(
+            !content.is_empty(),
+            "LLM response did not include message content"
+        );
+
+        Ok(Completion {
+            content,
+            cut_off: response.stop_reason.as_deref() == Some("max_tokens"),
+            usage: Usage {
+                prompt_tokens: response.usage.input_tokens,
+                completion_tokens: response.usage.output_tokens,
+                total_tokens: response.usage.input_tokens + response.usage.output_tokens,
+            },
+        })
+    }
+}
+
+/// An error response from the messages API.
+#[derive(Debug)]
+pub struct StatusError {
+    status: StatusCode,
+    message: String,
+    /// How long the API asked to wait before sending another request, only set for rate limits
+    /// and server errors.
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "LLM api returned an error ({}): {}",
+            self.status, self.message
+        )
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// Requests are retried when rate limited and on server errors, which includes the API being
+/// overloaded (529).
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Parses the `retry-after` header, which the API sends in seconds.
+fn retry_after_header(headers: &HeaderMap) -> Option<Duration> {
+    let seconds: f64 = headers
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<Message>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    stop_sequences: &'a [String],
+}
+
+#[derive(Debug, Serialize)]
+struct Message {
+    role: Role,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text { text: String },
+    Image { source: ImageSource },
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ImageSource {
+    Base64 { media_type: String, data: String },
+    Url { url: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    content: Vec<ResponseBlock>,
+    /// Why the LLM stopped generating, like `end_turn` or `max_tokens`.
+    stop_reason: Option<String>,
+    #[serde(default)]
+    usage: MessagesUsage,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MessagesUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseBlock {
+    Text {
+        text: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+/// Converts the chat history into a request for the messages API.
+///
+/// System messages are moved to the top level `system` field. Tool calls are not supported and are
+/// left out.
+fn build_request<'a>(
+    model_name: &'a str,
+    max_tokens: u32,
+    history: &[ChatCompletionRequestMessage],
+) -> MessagesRequest<'a> {
+    let mut system = Vec::new();
+    let mut messages = Vec::new();
+
+    for message in history {
+        match message {
+            ChatCompletionRequestMessage::System(message) => match &message.content {
+                ChatCompletionRequestSystemMessageContent::Text(text) => system.push(text.clone()),
+                ChatCompletionRequestSystemMessageContent::Array(parts) => {
+                    system.extend(parts.iter().map(|part| match part {
+                        ChatCompletionRequestSystemMessageContentPart::Text(part) => {
+                            part.text.clone()
+                        }
+                    }))
+                }
+            },
+            ChatCompletionRequestMessage::Developer(message) => match &message.content {
+                ChatCompletionRequestDeveloperMessageContent::Text(text) => {
+                    system.push(text.clone())
+                }
+                ChatCompletionRequestDeveloperMessageContent::Array(parts) => {
+                    system.extend(parts.iter().map(|part| part.text.clone()))
+                }
+            },
+            ChatCompletionRequestMessage::User(message) => {
+                let content = match &message.content {
+                    ChatCompletionRequestUserMessageContent::Text(text) => {
+                        vec![ContentBlock::Text { text: text.clone() }]
+                    }
+                    ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                        .iter()
+                        .filter_map(|part| match part {
+                            ChatCompletionRequestUserMessageContentPart::Text(part) => {
+                                Some(ContentBlock::Text {
+                                    text: part.text.clone(),
+                                })
+                            }
+                            ChatCompletionRequestUserMessageContentPart::ImageUrl(part) => {
+                                Some(ContentBlock::Image {
+                                    source: image_source(&part.image_url.url),
+                                })
+                            }
+                            _ => None,
+                        })
+                        .collect(),
+                };
+                messages.push(Message {
+                    role: Role::User,
+                    content,
+                });
+            }
+            ChatCompletionRequestMessage::Assistant(message) => {
+                let text = match &message.content {
+                    Some(ChatCompletionRequestAssistantMessageContent::Text(text)) => text.clone(),
+                    Some(ChatCompletionRequestAssistantMessageContent::Array(parts)) => parts
+                        .iter()
+                        .filter_map(|part| match part {
+                            ChatCompletionRequestAssistantMessageContentPart::Text(part) => {
+                                Some(part.text.as_str())
+                            }
+                            _ => None,
+                        })
+                        .collect(),
+                    // Only contains tool calls.
+                    None => continue,
+                };
+                messages.push(Message {
+                    role: Role::Assistant,
+                    content: vec![ContentBlock::Text { text }],
+                });
+            }
+            _ => {}
+        }
+    }
+
+    MessagesRequest {
+        model: model_name,
+        max_tokens,
+        system: (!system.is_empty()).then(|| system.join("\n\n")),
+        messages,
+        stop_sequences: &[],
+    }
+}
+
+/// Images are sent to the OpenAI API as `data:` urls, the messages API needs the data and media
+/// type separately.
+fn image_source(url: &str) -> ImageSource {
+    let data_url = url
+        .strip_prefix("data:")
+        .and_then(|url| url.split_once(";base64,"));
+
+    match data_url {
+        Some((media_type, data)) => ImageSource::Base64 {
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        },
+        None => ImageSource::Url {
+            url: url.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The system prompt must be moved out of the messages.
+    #[test]
+    fn system_prompt_is_top_level() {
+        let history = [
+            ChatCompletionRequestMessage::System("prompt".into()),
+            ChatCompletionRequestMessage::User("hello".into()),
+            ChatCompletionRequestMessage::Assistant("hi".into()),
+        ];
+
+        let request = build_request("model", 400, &history);
+
+        assert_eq!(request.system.as_deref(), Some("prompt"));
+        assert_eq!(request.messages.len(), 2);
+        assert_eq!(request.messages[0].role, Role::User);
+        assert_eq!(request.messages[1].role, Role::Assistant);
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after_header(&headers), None);
+
+        headers.insert(RETRY_AFTER, "12".parse().unwrap());
+        assert_eq!(retry_after_header(&headers), Some(Duration::from_secs(12)));
+
+        headers.insert(
+            RETRY_AFTER,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        assert_eq!(retry_after_header(&headers), None);
+    }
+
+    #[test]
+    fn overloaded_is_retried() {
+        assert!(is_retryable(StatusCode::TOO_MANY_REQUESTS));
+        assert!(is_retryable(StatusCode::from_u16(529).unwrap()));
+        assert!(!is_retryable(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn data_url_is_split() {
+        assert_eq!(
+            image_source("data:image/jpeg;base64,AAAA"),
+            ImageSource::Base64 {
+                media_type: "image/jpeg".to_string(),
+                data: "AAAA".to_string(),
+            }
+        );
+        assert_eq!(
+            image_source("https://example.com/image.png"),
+            ImageSource::Url {
+                url: "https://example.com/image.png".to_string(),
+            }
+        );
+    }
+}
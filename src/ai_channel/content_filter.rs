@@ -0,0 +1,127 @@
+use std::borrow::Cow;
+
+use anyhow::Context;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Configuration {
+    /// Words and phrases that are not allowed in responses. Matching is case-insensitive and also
+    /// matches inside of other words.
+    blocklist: Vec<String>,
+    /// What to do with responses containing blocked words.
+    #[serde(default)]
+    mode: FilterMode,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterMode {
+    /// Don't send the response at all.
+    #[default]
+    Drop,
+    /// Replace every character of the blocked words with `█`.
+    Censor,
+}
+
+/// Filters blocked words out of the LLM responses.
+#[derive(Debug)]
+pub struct ContentFilter {
+    blocklist: Regex,
+    mode: FilterMode,
+}
+
+impl ContentFilter {
+    pub fn new(config: &Configuration) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            config.blocklist.iter().all(|word| !word.is_empty()),
+            "the blocklist must not contain empty words"
+        );
+
+        let pattern = config
+            .blocklist
+            .iter()
+            .map(|word| regex::escape(word))
+            .collect::<Vec<_>>()
+            .join("|");
+        let blocklist = RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .context("failed to build the blocklist")?;
+
+        Ok(Self {
+            blocklist,
+            mode: config.mode,
+        })
+    }
+
+    /// Applies the filter to a response.
+    ///
+    /// Returns `None` if the response should not be sent.
+    pub fn apply<'a>(&self, content: &'a str) -> Option<Cow<'a, str>> {
+        if self.blocklist.as_str().is_empty() || !self.blocklist.is_match(content) {
+            return Some(Cow::Borrowed(content));
+        }
+
+        match self.mode {
+            FilterMode::Drop => None,
+            FilterMode::Censor => Some(
+                self.blocklist
+                    .replace_all(content, |captures: &regex::Captures| {
+                        "█".repeat(captures[0].chars().count())
+                    }),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(blocklist: &[&str], mode: FilterMode) -> ContentFilter {
+        ContentFilter::new(&Configuration {
+            blocklist: blocklist.iter().map(|word| word.to_string()).collect(),
+            mode,
+        })
+        .expect("Unable to create content filter")
+    }
+
+    /// Responses without blocked words must be left untouched.
+    #[test]
+    fn clean_response_unchanged() {
+        let filter = filter(&["bad"], FilterMode::Drop);
+
+        assert_eq!(
+            filter.apply("a perfectly fine message").as_deref(),
+            Some("a perfectly fine message")
+        );
+    }
+
+    /// Blocked words must be matched regardless of case.
+    #[test]
+    fn drop_case_insensitive() {
+        let filter = filter(&["bad word"], FilterMode::Drop);
+
+        assert_eq!(filter.apply("this is a BaD WoRd").as_deref(), None);
+    }
+
+    /// Censoring must replace every character of the blocked word, including multibyte ones.
+    #[test]
+    fn censor_replaces_words() {
+        let filter = filter(&["bad", "ünicode"], FilterMode::Censor);
+
+        assert_eq!(
+            filter.apply("Bad ÜNICODE stays bad").as_deref(),
+            Some("███ ███████ stays ███")
+        );
+    }
+
+    /// An empty blocklist must not filter anything.
+    #[test]
+    fn empty_blocklist() {
+        let filter = filter(&[], FilterMode::Drop);
+
+        assert_eq!(filter.apply("anything").as_deref(), Some("anything"));
+    }
+}
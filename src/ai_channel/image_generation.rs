@@ -0,0 +1,182 @@
+use anyhow::Context;
+use async_openai::{
+    Client as AIClient,
+    config::OpenAIConfig,
+    types::{CreateImageRequestArgs, Image, ImageModel},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+use twilight_http::Client;
+use twilight_model::{channel::message::AllowedMentions, http::attachment::Attachment};
+
+use super::user_message::UserMessage;
+use crate::error::ErrorReporter;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Configuration {
+    /// The image model to use, like `dall-e-3`.
+    pub model_name: String,
+    /// The size of the generated images.
+    #[serde(default)]
+    size: ImageSize,
+    /// The name of the command that generates an image, used after the command prefix.
+    #[serde(default = "default_command")]
+    pub command: String,
+}
+
+fn default_command() -> String {
+    "draw".to_string()
+}
+
+/// The sizes images can be generated in, not every model supports every size.
+#[derive(Debug, Deserialize, Serialize, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ImageSize {
+    #[serde(rename = "256x256")]
+    S256x256,
+    #[serde(rename = "512x512")]
+    S512x512,
+    #[default]
+    #[serde(rename = "1024x1024")]
+    S1024x1024,
+    #[serde(rename = "1792x1024")]
+    S1792x1024,
+    #[serde(rename = "1024x1792")]
+    S1024x1792,
+}
+
+impl From<ImageSize> for async_openai::types::ImageSize {
+    fn from(size: ImageSize) -> Self {
+        match size {
+            ImageSize::S256x256 => Self::S256x256,
+            ImageSize::S512x512 => Self::S512x512,
+            ImageSize::S1024x1024 => Self::S1024x1024,
+            ImageSize::S1792x1024 => Self::S1792x1024,
+            ImageSize::S1024x1792 => Self::S1024x1792,
+        }
+    }
+}
+
+impl Configuration {
+    /// Checks that the configuration values are sensible.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !self.model_name.is_empty(),
+            "`model_name` must not be empty"
+        );
+        anyhow::ensure!(
+            !self.command.is_empty() && !self.command.contains(char::is_whitespace),
+            "`command` must not be empty or contain whitespace"
+        );
+        Ok(())
+    }
+}
+
+/// Generates the image described by `prompt` and sends it as a reply to `msg`.
+///
+/// Errors are reported in the channel.
+pub async fn respond(
+    config: &Configuration,
+    client: &AIClient<OpenAIConfig>,
+    download: &reqwest::Client,
+    http: &Client,
+    errors: &ErrorReporter,
+    msg: &UserMessage,
+    prompt: &str,
+) {
+    let create_message = http
+        .create_message(msg.channel_id)
+        .reply(msg.message_id)
+        .fail_if_not_exists(false)
+        .allowed_mentions(Some(&AllowedMentions::default()));
+
+    if prompt.is_empty() {
+        let usage = format!(
+            "Describe the image after the command, like `{} a cat`.",
+            msg.content.trim()
+        );
+        if let Err(err) = create_message.content(&usage).await {
+            error!("Failed to send the image generation usage: {err}");
+        }
+        return;
+    }
+
+    let image = match generate(config, client, download, prompt).await {
+        Ok(image) => image,
+        Err(err) => {
+            error!("Error generating image: {err:?}");
+            errors
+                .report(
+                    http,
+                    "Image generation",
+                    msg.channel_id,
+                    &format!("Something went wrong while generating the image\n```\n{err}\n```"),
+                )
+                .await;
+            return;
+        }
+    };
+
+    let attachment = Attachment::from_bytes("image.png".to_string(), image, 1);
+    if let Err(err) = create_message.attachments(&[attachment]).await {
+        error!("Failed to send the generated image: {err}");
+    }
+}
+
+/// Generates an image and returns its contents.
+///
+/// Depending on the model the API returns either a link to the image, which is downloaded, or the
+/// image itself. `download` must not send the credentials of the API, the link can point at any
+/// host.
+async fn generate(
+    config: &Configuration,
+    client: &AIClient<OpenAIConfig>,
+    download: &reqwest::Client,
+    prompt: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let request = CreateImageRequestArgs::default()
+        .prompt(prompt)
+        .model(ImageModel::Other(config.model_name.clone()))
+        .size(async_openai::types::ImageSize::from(config.size))
+        .n(1)
+        .build()
+        .context("Failed to build request")?;
+
+    let response = client
+        .images()
+        .create(request)
+        .await
+        .context("Image api returned an error")?;
+    let image = response
+        .data
+        .first()
+        .context("Image api response did not include an image")?;
+
+    match image.as_ref() {
+        Image::Url { url, .. } => Ok(download
+            .get(url.as_str())
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .context("Failed to download the generated image")?
+            .bytes()
+            .await
+            .context("Failed to download the generated image")?
+            .to_vec()),
+        Image::B64Json { b64_json, .. } => BASE64_STANDARD
+            .decode(b64_json.as_str())
+            .context("Image api returned an invalid image"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_deserialized() {
+        let size: ImageSize = serde_json::from_str(r#""1792x1024""#).unwrap();
+        assert_eq!(size, ImageSize::S1792x1024);
+        assert!(serde_json::from_str::<ImageSize>(r#""1000x1000""#).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::VecDeque, sync::LazyLock};
+
+use regex::{Captures, Regex};
+use twilight_model::{
+    channel::message::{AllowedMentions, MentionType},
+    id::{Id, marker::UserMarker},
+};
+
+/// Discord does not allow more than 100 users in the allowed mentions.
+const MAX_ALLOWED_USERS: usize = 100;
+
+/// `@name` where name is made up of the characters allowed in usernames. Names directly preceded by
+/// a word character (like in email addresses) or `<` (already a mention) are ignored.
+static NAME_MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(^|[^\w<])@([\w.]+)").expect("invalid name mention regex"));
+
+/// Either an existing mention, or a bare snowflake id.
+static ID_MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<@!?\d+>|\b\d{17,20}\b").expect("invalid id mention regex"));
+
+/// Someone that recently sent a message in a conversation.
+#[derive(Debug)]
+pub struct Author {
+    pub id: Id<UserMarker>,
+    pub name: String,
+    pub display_name: Option<String>,
+}
+
+/// Converts the mentions the LLM wrote as plain text into discord mentions.
+///
+/// Both `@name` (using the username or display name) and bare user ids are converted, but only for
+/// the recent authors of the conversation.
+pub fn convert_mentions(content: &str, authors: &VecDeque<Author>) -> String {
+    let content = NAME_MENTION.replace_all(content, |captures: &Captures| {
+        let prefix = &captures[1];
+        // A name at the end of a sentence is followed by a period, which is not part of the name.
+        let name = captures[2].trim_end_matches('.');
+        let trailing = &captures[2][name.len()..];
+
+        let author = authors.iter().find(|author| {
+            author.name.eq_ignore_ascii_case(name)
+                || author
+                    .display_name
+                    .as_ref()
+                    .is_some_and(|display_name| display_name.to_lowercase() == name.to_lowercase())
+        });
+
+        match author {
+            Some(author) => format!("{prefix}<@{}>{trailing}", author.id),
+            None => captures[0].to_string(),
+        }
+    });
+
+    ID_MENTION
+        .replace_all(&content, |captures: &Captures| {
+            let text = &captures[0];
+            let is_recent_author = text
+                .parse::<u64>()
+                .is_ok_and(|id| authors.iter().any(|author| author.id.get() == id));
+
+            if is_recent_author {
+                format!("<@{text}>")
+            } else {
+                text.to_string()
+            }
+        })
+        .into_owned()
+}
+
+/// Only allows the recent authors of the conversation to be pinged.
+///
+/// `@everyone`, `@here` and roles can't be pinged unless they are explicitly allowed.
+pub fn allowed_mentions(
+    authors: &VecDeque<Author>,
+    allow_everyone: bool,
+    allow_roles: bool,
+) -> AllowedMentions {
+    let mut parse = Vec::new();
+    if allow_everyone {
+        parse.push(MentionType::Everyone);
+    }
+    if allow_roles {
+        parse.push(MentionType::Roles);
+    }
+
+    AllowedMentions {
+        parse,
+        users: authors
+            .iter()
+            .rev()
+            .take(MAX_ALLOWED_USERS)
+            .map(|author| author.id)
+            .collect(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authors() -> VecDeque<Author> {
+        VecDeque::from([
+            Author {
+                id: Id::new(111111111111111111),
+                name: "bob".to_string(),
+                display_name: Some("Bobby".to_string()),
+            },
+            Author {
+                id: Id::new(222222222222222222),
+                name: "alice.dev".to_string(),
+                display_name: None,
+            },
+        ])
+    }
+
+    #[test]
+    fn converts_names() {
+        assert_eq!(
+            convert_mentions("hey @bob and @Bobby, ask @alice.dev.", &authors()),
+            "hey <@111111111111111111> and <@111111111111111111>, ask <@222222222222222222>."
+        );
+    }
+
+    #[test]
+    fn converts_bare_ids() {
+        assert_eq!(
+            convert_mentions("user 222222222222222222 said so", &authors()),
+            "user <@222222222222222222> said so"
+        );
+    }
+
+    /// Existing mentions, unknown users, email addresses and everyone pings must be left alone.
+    #[test]
+    fn leaves_other_text() {
+        let content = "<@111111111111111111> @carol 333333333333333333 bob@bob.com @everyone @here";
+        assert_eq!(convert_mentions(content, &authors()), content);
+    }
+
+    #[test]
+    fn everyone_and_roles_disabled_by_default() {
+        let allowed = allowed_mentions(&authors(), false, false);
+        assert!(allowed.parse.is_empty());
+        assert_eq!(allowed.users.len(), 2);
+
+        let allowed = allowed_mentions(&authors(), true, true);
+        assert_eq!(allowed.parse, [MentionType::Everyone, MentionType::Roles]);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use regex::{Captures, Regex};
+use twilight_model::id::{Id, marker::UserMarker};
+
+use super::user_message::UserMessage;
+
+/// A mention of a user in a discord message, like `<@123>`.
+static USER_MENTION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<@!?(\d+)>").expect("invalid user mention regex"));
+
+/// A pseudonym written by the LLM, optionally as `@user_1`.
+static PSEUDONYM: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"@?\buser_(\d+)\b").expect("invalid pseudonym regex"));
+
+/// Gives every user a short pseudonym like `user_1`, so their ids are never sent to the LLM.
+///
+/// The pseudonyms are numbered in the order the users are first seen, and stay the same while the
+/// bot is running.
+#[derive(Debug, Default)]
+pub struct Pseudonyms {
+    numbers: HashMap<Id<UserMarker>, usize>,
+    /// The users in the order they got a pseudonym, `user_1` is the first.
+    users: Vec<Id<UserMarker>>,
+}
+
+impl Pseudonyms {
+    /// The pseudonym of the user, a new one is assigned if they don't have one yet.
+    pub fn get(&mut self, user_id: Id<UserMarker>) -> String {
+        let number = *self.numbers.entry(user_id).or_insert_with(|| {
+            self.users.push(user_id);
+            self.users.len()
+        });
+        format!("user_{number}")
+    }
+
+    /// The user with the pseudonym, if it was assigned.
+    pub fn resolve(&self, pseudonym: &str) -> Option<Id<UserMarker>> {
+        let number: usize = pseudonym.strip_prefix("user_")?.parse().ok()?;
+        self.users.get(number.checked_sub(1)?).copied()
+    }
+
+    /// Replaces the id of the sender and the users mentioned in the message by their pseudonyms.
+    pub fn anonymize(&mut self, msg: &mut UserMessage) {
+        msg.sender_pseudonym = Some(self.get(msg.sender_id));
+        msg.content = self.anonymize_mentions(&msg.content);
+    }
+
+    /// Replaces the user mentions in the content by `@` and the pseudonym of the user.
+    pub fn anonymize_mentions(&mut self, content: &str) -> String {
+        USER_MENTION
+            .replace_all(content, |captures: &Captures| {
+                match captures[1].parse::<u64>().ok().and_then(Id::new_checked) {
+                    Some(user_id) => format!("@{}", self.get(user_id)),
+                    None => captures[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+
+    /// Replaces the pseudonyms written by the LLM with mentions of the users, if `filter` allows
+    /// mentioning them.
+    pub fn convert_pseudonyms(
+        &self,
+        content: &str,
+        filter: impl Fn(Id<UserMarker>) -> bool,
+    ) -> String {
+        PSEUDONYM
+            .replace_all(content, |captures: &Captures| {
+                let pseudonym = captures[0].trim_start_matches('@');
+                match self.resolve(pseudonym) {
+                    Some(user_id) if filter(user_id) => format!("<@{user_id}>"),
+                    _ => captures[0].to_string(),
+                }
+            })
+            .into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pseudonyms_stable() {
+        let mut pseudonyms = Pseudonyms::default();
+        assert_eq!(pseudonyms.get(Id::new(500)), "user_1");
+        assert_eq!(pseudonyms.get(Id::new(300)), "user_2");
+        assert_eq!(pseudonyms.get(Id::new(500)), "user_1");
+
+        assert_eq!(pseudonyms.resolve("user_2"), Some(Id::new(300)));
+        assert_eq!(pseudonyms.resolve("user_0"), None);
+        assert_eq!(pseudonyms.resolve("user_3"), None);
+    }
+
+    #[test]
+    fn mentions_anonymized() {
+        let mut pseudonyms = Pseudonyms::default();
+        assert_eq!(
+            pseudonyms.anonymize_mentions("hi <@111111111111111111> and <@!222222222222222222>"),
+            "hi @user_1 and @user_2"
+        );
+    }
+
+    #[test]
+    fn pseudonyms_converted_back() {
+        let mut pseudonyms = Pseudonyms::default();
+        pseudonyms.get(Id::new(111111111111111111));
+        pseudonyms.get(Id::new(222222222222222222));
+
+        assert_eq!(
+            pseudonyms.convert_pseudonyms("thanks @user_1, user_2 and user_3", |user_id| {
+                user_id.get() == 111111111111111111
+            }),
+            "thanks <@111111111111111111>, user_2 and user_3"
+        );
+    }
+}
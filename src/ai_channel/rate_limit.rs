@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use async_openai::error::OpenAIError;
+
+use super::anthropic;
+
+/// Returns how long the LLM API asked to wait before sending another request, if the error was
+/// caused by hitting a rate limit.
+///
+/// The Anthropic client takes the delay from the `retry-after` header. `async-openai` does not
+/// expose the response headers, so the delay is taken from the error message instead (e.g.
+/// "Please try again in 20s."). Returns `None` if the error is not caused by a rate limit or the
+/// API did not say how long to wait.
+pub fn retry_after(err: &anyhow::Error) -> Option<Duration> {
+    if let Some(err) = err.downcast_ref::<anthropic::StatusError>() {
+        return err.retry_after;
+    }
+
+    let Some(OpenAIError::ApiError(api_error)) = err.downcast_ref::<OpenAIError>() else {
+        return None;
+    };
+
+    let message = api_error.message.to_lowercase();
+    let is_rate_limit = matches!(api_error.r#type.as_deref(), Some("requests" | "tokens"))
+        || message.contains("rate limit")
+        || message.contains("resource_exhausted");
+    if !is_rate_limit {
+        return None;
+    }
+
+    parse_retry_after(&message)
+}
+
+/// Parses the delay from messages containing "try again in {duration}".
+///
+/// The duration can consist of multiple parts, like `1m30s` or `1.5s`.
+fn parse_retry_after(message: &str) -> Option<Duration> {
+    let (_, rest) = message.split_once("try again in ")?;
+    let duration = rest.split_whitespace().next()?;
+    // Remove the punctuation at the end of the sentence.
+    let mut duration = duration.trim_end_matches(['.', ',']);
+
+    let mut total = Duration::ZERO;
+    while !duration.is_empty() {
+        let number_end = duration
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(duration.len());
+        let (number, rest) = duration.split_at(number_end);
+        let number: f64 = number.parse().ok()?;
+
+        let unit_end = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.len());
+        let (unit, rest) = rest.split_at(unit_end);
+        let seconds = match unit {
+            "ms" => number / 1000.0,
+            "s" => number,
+            "m" => number * 60.0,
+            "h" => number * 60.0 * 60.0,
+            _ => return None,
+        };
+
+        total += Duration::try_from_secs_f64(seconds).ok()?;
+        duration = rest;
+    }
+
+    Some(total)
+}
+
+/// The fraction by which [`with_jitter`] randomly lengthens or shortens a delay.
+const JITTER: f64 = 0.2;
+
+/// Randomly lengthens or shortens the delay by up to 20%.
+///
+/// Channels that use the same API often wait at the same time after a burst of messages, this
+/// keeps them from sending their requests in lockstep.
+pub fn with_jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::random_range(1.0 - JITTER..=1.0 + JITTER))
+}
+
+/// The time to wait between requests, adapting to how busy the channel is.
+///
+/// The delay halves when no messages are waiting after a response, so a quiet channel gets
+/// responses quickly, and doubles when messages are waiting so more messages are batched into a
+/// single response during bursts.
+#[derive(Debug)]
+pub struct AdaptiveDelay {
+    current: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl AdaptiveDelay {
+    /// Starts at `initial`, limited to the range from `min` to `max`.
+    pub fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+        Self {
+            current: initial.clamp(min, max),
+            min,
+            max,
+        }
+    }
+
+    /// Updates the delay after a response and returns the new delay.
+    pub fn update(&mut self, messages_waiting: bool) -> Duration {
+        self.current = if messages_waiting {
+            (self.current * 2).min(self.max)
+        } else {
+            (self.current / 2).max(self.min)
+        };
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_within_bounds() {
+        let delay = Duration::from_millis(1500);
+        for _ in 0..100 {
+            let jittered = with_jitter(delay);
+            assert!(jittered >= Duration::from_millis(1200));
+            assert!(jittered <= Duration::from_millis(1800));
+        }
+    }
+
+    #[test]
+    fn parse_seconds() {
+        assert_eq!(
+            parse_retry_after("rate limit reached. please try again in 20s. visit ..."),
+            Some(Duration::from_secs(20))
+        );
+    }
+
+    #[test]
+    fn parse_fractional_and_milliseconds() {
+        assert_eq!(
+            parse_retry_after("please try again in 1.5s."),
+            Some(Duration::from_millis(1500))
+        );
+        assert_eq!(
+            parse_retry_after("please try again in 250ms"),
+            Some(Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    fn parse_compound() {
+        assert_eq!(
+            parse_retry_after("please try again in 6m0s."),
+            Some(Duration::from_secs(360))
+        );
+    }
+
+    #[test]
+    fn parse_missing_or_invalid() {
+        assert_eq!(parse_retry_after("rate limit reached"), None);
+        assert_eq!(parse_retry_after("please try again in a moment"), None);
+    }
+
+    #[test]
+    fn delay_adapts_within_bounds() {
+        let mut delay = AdaptiveDelay::new(
+            Duration::from_millis(1500),
+            Duration::from_millis(500),
+            Duration::from_millis(5000),
+        );
+
+        assert_eq!(delay.update(false), Duration::from_millis(750));
+        assert_eq!(delay.update(false), Duration::from_millis(500));
+        assert_eq!(delay.update(false), Duration::from_millis(500));
+        assert_eq!(delay.update(true), Duration::from_millis(1000));
+        assert_eq!(delay.update(true), Duration::from_millis(2000));
+        assert_eq!(delay.update(true), Duration::from_millis(4000));
+        assert_eq!(delay.update(true), Duration::from_millis(5000));
+    }
+
+    /// Without a range the delay must not change.
+    #[test]
+    fn fixed_delay() {
+        let interval = Duration::from_millis(1500);
+        let mut delay = AdaptiveDelay::new(interval, interval, interval);
+
+        assert_eq!(delay.update(false), interval);
+        assert_eq!(delay.update(true), interval);
+    }
+}
@@ -0,0 +1,190 @@
+/// The metadata fields of the built-in message format, see `UserMessage::format_message`.
+const METADATA_FIELDS: [&str; 7] = [
+    "message_id",
+    "repling_to",
+    "replying_to",
+    "author",
+    "author_name",
+    "author_id",
+    "sent_at",
+];
+
+/// Removes the message format the LLM copied from the user messages into its response.
+///
+/// Only a `<msg>` tag at the start, a `</msg>` tag at the end and the metadata lines directly after
+/// the start are removed, so content that mentions these anywhere else is left alone.
+pub fn strip_format_leakage(content: &str) -> &str {
+    let mut content = content.trim();
+
+    if let Some(inner) = content.strip_prefix("<msg>") {
+        content = inner;
+    }
+    if let Some(inner) = content.strip_suffix("</msg>") {
+        content = inner;
+    }
+
+    loop {
+        let (line, rest) = content.split_once('\n').unwrap_or((content, ""));
+        if !is_metadata_line(line) {
+            break;
+        }
+        content = rest;
+    }
+
+    content.trim()
+}
+
+/// Removes the blocks between each pair of opening and closing tags, like the `<think>` blocks of
+/// reasoning models.
+///
+/// Nested blocks are removed as a whole and a block that is never closed is removed up to the end.
+/// Some APIs leave out the opening tag, so everything before a closing tag without an opening tag
+/// is removed as well.
+pub fn strip_tags(content: &str, tags: &[(String, String)]) -> String {
+    let mut content = content.to_string();
+    for (open, close) in tags {
+        content = strip_tag(&content, open, close);
+    }
+    content.trim().to_string()
+}
+
+fn strip_tag(content: &str, open: &str, close: &str) -> String {
+    let mut stripped = String::with_capacity(content.len());
+    let mut depth = 0usize;
+    let mut rest = content;
+    loop {
+        let next_open = rest.find(open);
+        let next_close = rest.find(close);
+        match (next_open, next_close) {
+            (Some(open_at), close_at) if close_at.is_none_or(|close_at| open_at < close_at) => {
+                if depth == 0 {
+                    stripped.push_str(&rest[..open_at]);
+                }
+                depth += 1;
+                rest = &rest[open_at + open.len()..];
+            }
+            (_, Some(close_at)) => {
+                if depth == 0 {
+                    // The opening tag was left out, everything so far was inside the block.
+                    stripped.clear();
+                } else {
+                    depth -= 1;
+                }
+                rest = &rest[close_at + close.len()..];
+            }
+            (_, None) => {
+                // A block that is never closed continues to the end.
+                if depth == 0 {
+                    stripped.push_str(rest);
+                }
+                return stripped;
+            }
+        }
+    }
+}
+
+/// Whether the line is one of the metadata lines of the built-in format, like `author_id: 123`.
+fn is_metadata_line(line: &str) -> bool {
+    line.split_once(':').is_some_and(|(field, value)| {
+        METADATA_FIELDS.contains(&field.trim()) && !value.trim().is_empty()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_wrapped_response() {
+        assert_eq!(
+            strip_format_leakage(
+                "<msg>message_id: 1\nauthor_name: bot\nauthor_id: 2\nsent_at: 2025-01-01T00:00:00.000Z\nHello!</msg>"
+            ),
+            "Hello!"
+        );
+    }
+
+    #[test]
+    fn strip_metadata_without_tags() {
+        assert_eq!(
+            strip_format_leakage("author_name: bot\nauthor_id: 2\nHi there\nHow are you?"),
+            "Hi there\nHow are you?"
+        );
+        assert_eq!(strip_format_leakage("Sure!</msg>"), "Sure!");
+    }
+
+    #[test]
+    fn only_metadata_is_empty() {
+        assert_eq!(strip_format_leakage("<msg>message_id: 1</msg>"), "");
+    }
+
+    fn think_tags() -> Vec<(String, String)> {
+        vec![("<think>".to_string(), "</think>".to_string())]
+    }
+
+    #[test]
+    fn strip_think_block() {
+        assert_eq!(
+            strip_tags("<think>The user greets me.</think>\nHello!", &think_tags()),
+            "Hello!"
+        );
+        assert_eq!(
+            strip_tags("Hi <think>a</think>there<think>b</think>!", &think_tags()),
+            "Hi there!"
+        );
+        assert_eq!(strip_tags("Hello!", &think_tags()), "Hello!");
+    }
+
+    #[test]
+    fn strip_nested_tags() {
+        assert_eq!(
+            strip_tags(
+                "<think>outer <think>inner</think> still thinking</think>Answer",
+                &think_tags()
+            ),
+            "Answer"
+        );
+    }
+
+    #[test]
+    fn strip_malformed_tags() {
+        // Unclosed blocks are removed up to the end.
+        assert_eq!(
+            strip_tags("Answer<think>cut off reasoning", &think_tags()),
+            "Answer"
+        );
+        assert_eq!(strip_tags("<think>only reasoning", &think_tags()), "");
+        // The opening tag was left out by the API.
+        assert_eq!(
+            strip_tags("reasoning without start</think>Answer", &think_tags()),
+            "Answer"
+        );
+    }
+
+    #[test]
+    fn strip_multiple_tag_pairs() {
+        let tags = vec![
+            ("<think>".to_string(), "</think>".to_string()),
+            ("<reasoning>".to_string(), "</reasoning>".to_string()),
+        ];
+        assert_eq!(
+            strip_tags("<reasoning>a</reasoning><think>b</think>Answer", &tags),
+            "Answer"
+        );
+    }
+
+    /// Content that merely looks like the format must not be changed.
+    #[test]
+    fn legitimate_content_unchanged() {
+        for content in [
+            "Hello!",
+            "The `<msg>` tag is used for messages.",
+            "Note: this has a colon",
+            "Hello!\nauthor_id: 2",
+            "message_id:\nis a field",
+            "```xml\n<msg>text</msg>\n```",
+        ] {
+            assert_eq!(strip_format_leakage(content), content);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use async_openai::types::{ChatCompletionTool, ChatCompletionToolType, FunctionObject};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use twilight_model::util::Timestamp;
+
+/// A built-in tool the LLM can call to get information it can't know by itself.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Tool {
+    /// Returns the current date and time in UTC.
+    CurrentTime,
+    /// Rolls one or more dice.
+    RollDice,
+}
+
+impl Tool {
+    fn name(self) -> &'static str {
+        match self {
+            Tool::CurrentTime => "current_time",
+            Tool::RollDice => "roll_dice",
+        }
+    }
+
+    /// The definition of the tool that is sent to the LLM.
+    pub fn definition(self) -> ChatCompletionTool {
+        let (description, parameters) = match self {
+            Tool::CurrentTime => (
+                "Get the current date and time in UTC.",
+                json!({ "type": "object", "properties": {} }),
+            ),
+            Tool::RollDice => (
+                "Roll one or more dice and get the results.",
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "sides": {
+                            "type": "integer",
+                            "description": "The amount of sides of each die, defaults to 6.",
+                        },
+                        "count": {
+                            "type": "integer",
+                            "description": "The amount of dice to roll, defaults to 1.",
+                        },
+                    },
+                }),
+            ),
+        };
+
+        ChatCompletionTool {
+            r#type: ChatCompletionToolType::Function,
+            function: FunctionObject {
+                name: self.name().to_string(),
+                description: Some(description.to_string()),
+                parameters: Some(parameters),
+                strict: None,
+            },
+        }
+    }
+
+    /// Runs the tool, returning the result that is sent back to the LLM.
+    ///
+    /// Errors are returned as a result as well, so the LLM can see what went wrong.
+    fn call(self, arguments: &str) -> String {
+        match self {
+            Tool::CurrentTime => current_time(),
+            Tool::RollDice => roll_dice(arguments),
+        }
+    }
+}
+
+/// Calls the tool named `name` if it is one of the enabled `tools`.
+pub fn call(tools: &[Tool], name: &str, arguments: &str) -> String {
+    match tools.iter().find(|tool| tool.name() == name) {
+        Some(tool) => tool.call(arguments),
+        None => format!("error: there is no tool named '{name}'"),
+    }
+}
+
+fn current_time() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
+
+    match Timestamp::from_secs(now) {
+        Ok(timestamp) => timestamp.iso_8601().to_string(),
+        Err(err) => format!("error: unable to get the current time: {err}"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RollDiceArguments {
+    #[serde(default = "default_sides")]
+    sides: u32,
+    #[serde(default = "default_count")]
+    count: u32,
+}
+
+fn default_sides() -> u32 {
+    6
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+fn roll_dice(arguments: &str) -> String {
+    // Some models send no arguments at all when all of them are optional.
+    let arguments = if arguments.trim().is_empty() {
+        "{}"
+    } else {
+        arguments
+    };
+
+    let arguments: RollDiceArguments = match serde_json::from_str(arguments) {
+        Ok(arguments) => arguments,
+        Err(err) => return format!("error: invalid arguments: {err}"),
+    };
+
+    if !(2..=1000).contains(&arguments.sides) {
+        return "error: dice must have between 2 and 1000 sides".to_string();
+    }
+    if !(1..=100).contains(&arguments.count) {
+        return "error: between 1 and 100 dice can be rolled at once".to_string();
+    }
+
+    let rolls: Vec<_> = (0..arguments.count)
+        .map(|_| rand::random_range(1..=arguments.sides).to_string())
+        .collect();
+    rolls.join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Rolls must stay within the amount of sides and return one result per die.
+    #[test]
+    fn roll_dice_in_range() {
+        let result = roll_dice(r#"{"sides": 4, "count": 50}"#);
+        let rolls: Vec<u32> = result
+            .split(", ")
+            .map(|roll| roll.parse().expect("Roll is not a number"))
+            .collect();
+
+        assert_eq!(rolls.len(), 50);
+        assert!(rolls.iter().all(|roll| (1..=4).contains(roll)));
+    }
+
+    /// Invalid arguments must be reported back instead of panicking.
+    #[test]
+    fn roll_dice_invalid_arguments() {
+        assert!(roll_dice(r#"{"sides": 0}"#).starts_with("error"));
+        assert!(roll_dice("not json").starts_with("error"));
+    }
+
+    /// Only enabled tools may be called.
+    #[test]
+    fn call_disabled_tool() {
+        assert!(call(&[Tool::RollDice], "current_time", "").starts_with("error"));
+        assert!(!call(&[Tool::CurrentTime], "current_time", "").starts_with("error"));
+    }
+}
@@ -0,0 +1,92 @@
+use std::ops::AddAssign;
+
+use serde::Deserialize;
+use tracing::{debug, info};
+use twilight_model::id::{Id, marker::ChannelMarker};
+
+/// The amount of tokens used by a request to the LLM api.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    pub prompt_tokens: u64,
+    #[serde(default)]
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub total_tokens: u64,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+        self.total_tokens += rhs.total_tokens;
+    }
+}
+
+/// Logs the tokens used by an AI channel.
+#[derive(Debug)]
+pub struct UsageLog {
+    channel_id: Id<ChannelMarker>,
+    /// The total usage is logged every this many responses, when set.
+    log_interval: Option<u32>,
+    total: Usage,
+    responses: u32,
+}
+
+impl UsageLog {
+    pub fn new(channel_id: Id<ChannelMarker>, log_interval: Option<u32>) -> Self {
+        Self {
+            channel_id,
+            log_interval,
+            total: Usage::default(),
+            responses: 0,
+        }
+    }
+
+    /// Logs the usage of a single response and adds it to the total.
+    pub fn record(&mut self, usage: Usage) {
+        debug!(
+            channel_id = %self.channel_id,
+            prompt_tokens = usage.prompt_tokens,
+            completion_tokens = usage.completion_tokens,
+            total_tokens = usage.total_tokens,
+            "Token usage of response"
+        );
+
+        self.total += usage;
+        self.responses += 1;
+
+        if self
+            .log_interval
+            .is_some_and(|interval| self.responses % interval == 0)
+        {
+            info!(
+                channel_id = %self.channel_id,
+                responses = self.responses,
+                prompt_tokens = self.total.prompt_tokens,
+                completion_tokens = self.total.completion_tokens,
+                total_tokens = self.total.total_tokens,
+                "Total token usage"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Some APIs leave out fields, those must count as zero tokens.
+    #[test]
+    fn missing_fields_are_zero() {
+        let usage: Usage = serde_json::from_str(r#"{"prompt_tokens": 10}"#).unwrap();
+        assert_eq!(
+            usage,
+            Usage {
+                prompt_tokens: 10,
+                completion_tokens: 0,
+                total_tokens: 0,
+            }
+        );
+    }
+}
@@ -1,51 +1,197 @@
-use std::{io::Cursor, sync::Arc};
+use std::{io::Cursor, sync::Arc, time::Duration};
 
+use anyhow::Context;
 use async_openai::types::{
     ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestUserMessage,
     ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
     ImageDetail, ImageUrl,
 };
+use backoff::{ExponentialBackoff, backoff::Backoff};
 use base64::{Engine, prelude::BASE64_STANDARD};
-use image::{GenericImageView, ImageFormat, ImageReader, imageops::FilterType};
-use tokio::sync::{broadcast, mpsc};
-use tracing::error;
+use image::{
+    AnimationDecoder, DynamicImage, GenericImageView, ImageFormat, ImageReader,
+    codecs::gif::GifDecoder, imageops::FilterType,
+};
+use metrics::counter;
+use tokio::{
+    sync::{Semaphore, broadcast, mpsc},
+    time::{Instant, sleep},
+};
+use tracing::{Instrument, debug, error, warn};
+use twilight_cache_inmemory::InMemoryCache;
 use twilight_gateway::Event;
+use twilight_http::Client;
 use twilight_model::{
+    channel::{Attachment, Message, message::MessageType},
+    guild::Permissions,
     id::{
         Id,
-        marker::{ChannelMarker, MessageMarker, UserMarker},
+        marker::{
+            AttachmentMarker, ChannelMarker, GuildMarker, MessageMarker, RoleMarker, UserMarker,
+        },
     },
     util::Timestamp,
 };
 
+use super::{ImageDetailLevel, ResizeFilter, Shared, access::Access};
+use crate::commands;
+
+/// An item in the queue of an AI channel.
+#[derive(Debug)]
+pub enum Queued {
+    Message(UserMessage),
+    /// A message that was edited, with its new content.
+    Edit(UserMessage),
+    /// The conversation in the channel, either the AI channel or one of its threads, was reset.
+    Reset(Id<ChannelMarker>),
+}
+
 #[derive(Debug)]
 pub struct UserMessage {
+    /// The id of the message, or of the most recent message when messages were merged.
     pub message_id: Id<MessageMarker>,
+    /// The ids of the earlier messages that were merged into this one, oldest first.
+    pub merged_ids: Vec<Id<MessageMarker>>,
+    /// The channel the message was sent in. This is either the AI channel or one of its threads.
+    pub channel_id: Id<ChannelMarker>,
+    pub guild_id: Option<Id<GuildMarker>>,
     pub reply_to: Option<Id<MessageMarker>>,
     pub content: String,
     pub sender_name: String,
     pub sender_display_name: Option<String>,
     pub sender_id: Id<UserMarker>,
+    /// The roles of the sender in the guild the message was sent in.
+    pub sender_roles: Vec<Id<RoleMarker>>,
     pub sent_at: Timestamp,
-    pub images: Vec<String>,
+    /// All attachments of the message, the images among them are sent to the LLM when image
+    /// support is enabled.
+    pub attachments: Vec<Attachment>,
+    /// Whether the bot itself was mentioned in the message.
+    pub mentions_bot: bool,
+    /// Sent to the LLM instead of the id of the sender, when ids are anonymized.
+    pub sender_pseudonym: Option<String>,
 }
 
 impl UserMessage {
+    pub fn new(message: &Message, sender_roles: Vec<Id<RoleMarker>>, mentions_bot: bool) -> Self {
+        Self {
+            message_id: message.id,
+            merged_ids: Vec::new(),
+            channel_id: message.channel_id,
+            guild_id: message.guild_id,
+            reply_to: message.reference.as_ref().and_then(|r| r.message_id),
+            content: message.content.clone(),
+            sender_name: message.author.name.clone(),
+            sender_id: message.author.id,
+            sender_roles,
+            sent_at: message.timestamp,
+            sender_display_name: message
+                .member
+                .as_ref()
+                .and_then(|m| m.nick.clone())
+                .or_else(|| message.author.global_name.clone()),
+            attachments: message.attachments.clone(),
+            mentions_bot,
+            sender_pseudonym: None,
+        }
+    }
+
+    /// Merges `next` into this message when it was sent by the same author in the same channel
+    /// within `window`, otherwise `next` is returned.
+    ///
+    /// The contents are joined with a newline. Replies are never merged into an earlier message, so
+    /// the message they reply to stays clear.
+    pub fn merge(&mut self, next: UserMessage, window: Duration) -> Result<(), UserMessage> {
+        let elapsed_micros = next.sent_at.as_micros() - self.sent_at.as_micros();
+        let mergeable = next.sender_id == self.sender_id
+            && next.channel_id == self.channel_id
+            && next.reply_to.is_none()
+            && u128::try_from(elapsed_micros).is_ok_and(|elapsed| elapsed <= window.as_micros());
+        if !mergeable {
+            return Err(next);
+        }
+
+        self.merged_ids.push(self.message_id);
+        self.merged_ids.extend(next.merged_ids);
+        self.message_id = next.message_id;
+        if !next.content.is_empty() {
+            if !self.content.is_empty() {
+                self.content.push('\n');
+            }
+            self.content.push_str(&next.content);
+        }
+        self.sender_roles = next.sender_roles;
+        self.sender_display_name = next.sender_display_name;
+        self.sent_at = next.sent_at;
+        self.attachments.extend(next.attachments);
+        self.mentions_bot |= next.mentions_bot;
+        Ok(())
+    }
+
+    /// The ids of all messages merged into this one, separated by commas.
+    fn message_ids(&self) -> String {
+        self.merged_ids
+            .iter()
+            .chain([&self.message_id])
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
     /// Serialize the message into the format expected by the LLM.
-    pub fn format_message(&self) -> String {
+    ///
+    /// When a `template` is given its placeholders are replaced, otherwise the built-in format is
+    /// used. See [`TEMPLATE_PLACEHOLDERS`] for the supported placeholders.
+    ///
+    /// When `include_usernames` is false, the username of the sender is left out. The sender is
+    /// named by their display name instead, or by `sender_pseudonym` when they don't have one. The
+    /// id is always replaced by `sender_pseudonym` when it is set.
+    pub fn format_message(&self, template: Option<&str>, include_usernames: bool) -> String {
+        let author_id = || {
+            self.sender_pseudonym
+                .clone()
+                .unwrap_or_else(|| self.sender_id.to_string())
+        };
+        let author_label = || self.sender_display_name.clone().unwrap_or_else(author_id);
+
+        if let Some(template) = template {
+            return render_template(template, |placeholder| {
+                Some(match placeholder {
+                    "message_id" => self.message_ids(),
+                    "reply_to" => self.reply_to.map(|id| id.to_string()).unwrap_or_default(),
+                    "author_name" if !include_usernames => author_label(),
+                    "author_name" => self.sender_name.clone(),
+                    "author_display_name" if !include_usernames => author_label(),
+                    "author_display_name" => self.sender_display_name.clone().unwrap_or_default(),
+                    "author_id" => author_id(),
+                    "sent_at" => self.sent_at.iso_8601().to_string(),
+                    "content" => self.content.clone(),
+                    _ => return None,
+                })
+            });
+        }
+
+        let author = if include_usernames {
+            format!(
+                "author_name: {}{}\nauthor_id: {}",
+                self.sender_name,
+                match &self.sender_display_name {
+                    Some(name) => format!(" ({name})"),
+                    None => String::new(),
+                },
+                author_id(),
+            )
+        } else {
+            format!("author: {}", author_label())
+        };
+
         format!(
-            "<msg>message_id: {}\n{}author_name: {}\nauthor_id: {}{}\nsent_at: {}\n{}</msg>",
-            self.message_id,
+            "<msg>message_id: {}\n{}{author}\nsent_at: {}\n{}</msg>",
+            self.message_ids(),
             match self.reply_to {
                 Some(id) => format!("repling_to: {id}\n"),
                 None => String::new(),
             },
-            self.sender_name,
-            match &self.sender_display_name {
-                Some(name) => format!(" ({name})"),
-                None => String::new(),
-            },
-            self.sender_id,
             self.sent_at.iso_8601(),
             self.content
         )
@@ -55,24 +201,80 @@ impl UserMessage {
     pub async fn as_chat_completion_message(
         &self,
         config: &super::Configuration,
+        images: &ImageDownloader,
     ) -> ChatCompletionRequestUserMessage {
         if !config.image_support {
             // Not using the content parts ensures maximum compatibility.
-            return self.format_message().into();
+            return self
+                .format_message(config.message_template.as_deref(), config.include_usernames)
+                .into();
         }
 
+        let images_to_send: Vec<_> = self
+            .attachments
+            .iter()
+            .filter(|a| is_supported_image(a, config))
+            .collect();
+        // Lots of images would make the response slow and expensive, so the extra images are left
+        // out.
+        let omitted = images_to_send
+            .len()
+            .saturating_sub(config.max_images_per_message);
+
+        let mut text =
+            self.format_message(config.message_template.as_deref(), config.include_usernames);
+        if omitted > 0 {
+            text.push_str(&format!("\n({omitted} additional images omitted)"));
+        }
         let mut content = vec![ChatCompletionRequestUserMessageContentPart::Text(
-            self.format_message().into(),
+            text.into(),
         )];
 
-        for image in &self.images {
-            let image_b64 = match b64_encode_image(image, config.max_image_size).await {
-                Ok(v) => v,
+        // The images are downloaded and encoded concurrently, limited by the permits shared by all
+        // messages in the channel. The tasks are awaited in order to keep the order of the images.
+        let tasks: Vec<_> = images_to_send
+            .into_iter()
+            .take(config.max_images_per_message)
+            .map(|image| {
+                let attachment = AttachmentSource {
+                    channel_id: self.channel_id,
+                    message_id: self.message_id,
+                    attachment_id: image.id,
+                };
+                let url = image.url.clone();
+                let max_image_size = config.max_image_size;
+                let filter = match config.image_resize_filter {
+                    ResizeFilter::Nearest => FilterType::Nearest,
+                    ResizeFilter::Triangle => FilterType::Triangle,
+                    ResizeFilter::CatmullRom => FilterType::CatmullRom,
+                    ResizeFilter::Gaussian => FilterType::Gaussian,
+                    ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+                };
+                let images = images.clone();
+                tokio::spawn(
+                    async move {
+                        let result = images
+                            .b64_encode_image(&url, attachment, max_image_size, filter)
+                            .await;
+                        (url, result)
+                    }
+                    .in_current_span(),
+                )
+            })
+            .collect();
+
+        for task in tasks {
+            let image_b64 = match task.await {
+                Ok((_, Ok(v))) => v,
                 Err(err) => {
+                    error!("Image encoding task failed: {err}");
+                    continue;
+                }
+                Ok((url, Err(err))) => {
                     // Don't propagate the error up: there are a lot of reasons why encoding the
                     // image could go wrong, for example when the users povides an invalid image. It
                     // seems better to let the chat continue without errors in this case.
-                    error!("Failed to encode image: {err:?}");
+                    debug!("Skipping image {url}, failed to encode it: {err:?}");
                     continue;
                 }
             };
@@ -81,8 +283,13 @@ impl UserMessage {
                 ChatCompletionRequestMessageContentPartImage {
                     image_url: ImageUrl {
                         url: format!("data:image/jpeg;base64,{image_b64}"),
-                        // Images can be very expensive in terms of tokens.
-                        detail: Some(ImageDetail::Low),
+                        // Images can be very expensive in terms of tokens, so low detail is the
+                        // default.
+                        detail: Some(match config.image_detail {
+                            ImageDetailLevel::Low => ImageDetail::Low,
+                            ImageDetailLevel::High => ImageDetail::High,
+                            ImageDetailLevel::Auto => ImageDetail::Auto,
+                        }),
                     },
                 },
             ));
@@ -95,75 +302,631 @@ impl UserMessage {
     }
 }
 
-/// Queue incoming messages in a certain discord channel into a queue channel.
+/// The placeholders that can be used in a message template.
+pub const TEMPLATE_PLACEHOLDERS: [&str; 7] = [
+    "message_id",
+    "reply_to",
+    "author_name",
+    "author_display_name",
+    "author_id",
+    "sent_at",
+    "content",
+];
+
+/// Checks that the message template only uses known placeholders and includes the content.
+pub fn validate_template(template: &str) -> anyhow::Result<()> {
+    let mut has_content = false;
+    let mut unknown = None;
+    render_template(template, |placeholder| {
+        if placeholder == "content" {
+            has_content = true;
+        } else if !TEMPLATE_PLACEHOLDERS.contains(&placeholder) && unknown.is_none() {
+            unknown = Some(placeholder.to_string());
+        }
+        None
+    });
+
+    if let Some(unknown) = unknown {
+        anyhow::bail!(
+            "unknown placeholder `{{{unknown}}}`, supported placeholders are: {}",
+            TEMPLATE_PLACEHOLDERS.join(", ")
+        );
+    }
+    anyhow::ensure!(has_content, "the template must include `{{content}}`");
+    Ok(())
+}
+
+/// Replaces every `{placeholder}` in the template with the value returned by `value`.
+///
+/// Placeholders without a value are kept as is. The template is only scanned once, so braces in
+/// the inserted values are never replaced.
+fn render_template(template: &str, mut value: impl FnMut(&str) -> Option<String>) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        match value(&rest[1..end]) {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    rendered.push_str(rest);
+
+    rendered
+}
+
+/// The minimum time between warnings about messages being dropped because the queue is full.
+const FULL_QUEUE_WARNING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The amount of blank messages in a row after which a missing message content intent is likely.
+const BLANK_MESSAGES_WARNING: u32 = 5;
+
+/// Whether the message looks empty, which happens to messages of other users when the bot doesn't
+/// have the message content intent.
+///
+/// Messages that mention the bot still have their content without the intent, and forwarded
+/// messages are left out as their content is in a snapshot.
+fn is_blank(message: &Message, mentions_bot: bool) -> bool {
+    message.kind == MessageType::Regular
+        && message.reference.is_none()
+        && !mentions_bot
+        && message.content.is_empty()
+        && message.attachments.is_empty()
+        && message.embeds.is_empty()
+        && message.sticker_items.is_empty()
+        && message.components.is_empty()
+}
+
+/// Queue incoming messages in the AI channel of `config` into a queue channel.
+///
+/// When `include_threads` is set, messages sent in threads of the channel are queued as well.
+/// Messages from users that are not allowed by `access` are ignored. Edited messages are queued
+/// as edits when `edit_reply_on_edit` is set. Reset commands, both application commands and
+/// messages starting with the command prefix, reset the conversation they are used in, application
+/// commands are answered using `http` once the reset is queued. Commands from the owners in
+/// `shared` are always accepted. Pause and resume commands are left to `commands::serve`.
 pub async fn queue_messages(
     mut events: broadcast::Receiver<Arc<Event>>,
-    queue: mpsc::Sender<UserMessage>,
-    channel_id: Id<ChannelMarker>,
+    queue: mpsc::Sender<Queued>,
+    config: super::Configuration,
+    http: Arc<Client>,
+    cache: Arc<InMemoryCache>,
+    access: Access,
+    shared: Arc<Shared>,
 ) {
+    let channel_id = config.channel_id;
+    let include_threads = config.include_threads;
+    let channel_label = [("channel_id", channel_id.to_string())];
+    let queued_counter = counter!("bot_messages_queued_total", &channel_label);
+    let dropped_counter = counter!("bot_messages_dropped_total", &channel_label);
+
+    // The id of the bot, known once a shard is ready.
+    let mut bot_id = None;
+    let mut last_full_warning: Option<Instant> = None;
+    let mut dropped_since_warning = 0;
+    let mut blank_messages = 0;
+
     loop {
         let event = events.recv().await;
-        let message = match event.as_deref() {
+        let (message, edited): (&Message, bool) = match event.as_deref() {
             Err(broadcast::error::RecvError::Closed) => return,
-            Err(_) => continue,
-            Ok(Event::MessageCreate(msg)) => msg,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                crate::log_lagged_events("AI channel", skipped);
+                continue;
+            }
+            Ok(Event::Ready(ready)) => {
+                bot_id = Some(ready.user.id);
+                continue;
+            }
+            Ok(Event::InteractionCreate(interaction)) => {
+                let Some(channel) = commands::command_channel(interaction, commands::RESET) else {
+                    continue;
+                };
+                if !commands::is_in_channel(channel, channel_id, include_threads) {
+                    continue;
+                }
+                let allowed = interaction
+                    .author_id()
+                    .is_some_and(|user_id| shared.is_owner(user_id))
+                    || commands::interaction_has_permissions(
+                        interaction,
+                        Permissions::MANAGE_MESSAGES,
+                    );
+                if !allowed {
+                    commands::respond(
+                        &http,
+                        interaction,
+                        "You need the \"Manage Messages\" permission to reset the conversation.",
+                    )
+                    .await;
+                    continue;
+                }
+                // The queue is only closed when the AI channel is stopping.
+                let queued = queue.send(Queued::Reset(channel.id)).await.is_ok();
+                let content = if queued {
+                    "The conversation has been reset."
+                } else {
+                    "The conversation could not be reset, please try again later."
+                };
+                commands::respond(&http, interaction, content).await;
+                if !queued {
+                    return;
+                }
+                continue;
+            }
+            // Deleted threads can't be talked in anymore, so their conversation is forgotten.
+            Ok(Event::ThreadDelete(thread)) => {
+                if include_threads
+                    && thread.parent_id == channel_id
+                    && queue.send(Queued::Reset(thread.id)).await.is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+            Ok(Event::MessageCreate(msg)) => (msg, false),
+            // Message updates are also sent when an embed was added to a message.
+            // Edits are only queued when responses are edited, so they don't fill the queue.
+            Ok(Event::MessageUpdate(update))
+                if config.edit_reply_on_edit && update.edited_timestamp.is_some() =>
+            {
+                (update, true)
+            }
             Ok(_) => continue,
         };
 
-        if message.channel_id != channel_id || message.author.bot {
+        let in_channel = message.channel_id == channel_id
+            || (include_threads && is_thread_of(&cache, message.channel_id, channel_id));
+        if !in_channel || message.author.bot {
             continue;
         }
 
-        let res = queue.try_send(UserMessage {
-            message_id: message.id,
-            reply_to: message.reference.as_ref().and_then(|r| r.message_id),
-            content: message.content.clone(),
-            sender_name: message.author.name.clone(),
-            sender_id: message.author.id,
-            sent_at: message.timestamp,
-            sender_display_name: message
-                .member
-                .as_ref()
-                .and_then(|m| m.nick.clone())
-                .or_else(|| message.author.global_name.clone()),
-            images: message
-                .attachments
-                .iter()
-                .filter_map(|a| {
-                    let extension = a.filename.rsplit('.').next();
-                    match extension {
-                        Some("jpeg" | "jpg" | "png" | "webp") => Some(a.url.clone()),
-                        _ => None,
-                    }
-                })
-                .collect(),
+        let mentions_bot = bot_id.is_some_and(|bot_id| mentions_user(message, bot_id));
+        // Edits of messages are not counted, as their content was already seen before.
+        if !edited {
+            if is_blank(message, mentions_bot) {
+                blank_messages += 1;
+                if blank_messages == BLANK_MESSAGES_WARNING {
+                    warn!(
+                        "The last {blank_messages} messages in {channel_id} were empty, the bot \
+                         can't read messages. Make sure the \"Message Content Intent\" is enabled \
+                         for the bot in the discord developer portal"
+                    );
+                }
+            } else {
+                blank_messages = 0;
+            }
+        }
+
+        if !edited
+            && let Some((commands::RESET, _)) =
+                commands::parse_command(&message.content, &shared.command_prefix)
+        {
+            if shared.is_owner(message.author.id)
+                || commands::has_permissions(
+                    &cache,
+                    message.author.id,
+                    message.channel_id,
+                    Permissions::MANAGE_MESSAGES,
+                )
+            {
+                if queue.send(Queued::Reset(message.channel_id)).await.is_err() {
+                    return;
+                }
+            } else {
+                debug!(
+                    "Ignoring reset command {} from {}, they don't have the manage messages \
+                     permission",
+                    message.id, message.author.id
+                );
+            }
+            continue;
+        }
+
+        // Pause and resume commands are applied by `commands::serve`, they are not part of the
+        // conversation.
+        if !edited
+            && let Some((commands::PAUSE | commands::RESUME, _)) =
+                commands::parse_command(&message.content, &shared.command_prefix)
+        {
+            continue;
+        }
+
+        let sender_roles = sender_roles(message, &cache);
+        if !access.is_allowed(message.author.id, &sender_roles) {
+            debug!(
+                "Ignoring message {} from {}, they are not allowed to use the AI channel",
+                message.id, message.author.id
+            );
+            continue;
+        }
+        if !access.is_old_enough(
+            message.author.id,
+            joined_at(message, &cache),
+            message.timestamp,
+        ) {
+            debug!(
+                "Ignoring message {} from {}, their account or membership is too new",
+                message.id, message.author.id
+            );
+            continue;
+        }
+
+        let msg = UserMessage::new(message, sender_roles, mentions_bot);
+        let res = queue.try_send(if edited {
+            Queued::Edit(msg)
+        } else {
+            Queued::Message(msg)
         });
 
-        if let Err(mpsc::error::TrySendError::Closed(_)) = res {
-            return;
+        match res {
+            Ok(()) => {
+                queued_counter.increment(1);
+            }
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                dropped_counter.increment(1);
+                dropped_since_warning += 1;
+
+                // Warn at most once per interval, a full queue usually means many messages are
+                // dropped in a short time.
+                if last_full_warning.is_none_or(|last_warning| {
+                    last_warning.elapsed() >= FULL_QUEUE_WARNING_INTERVAL
+                }) {
+                    warn!(
+                        "The queue of AI channel {channel_id} is full, dropped \
+                         {dropped_since_warning} message(s) since the last warning"
+                    );
+                    last_full_warning = Some(Instant::now());
+                    dropped_since_warning = 0;
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return,
         }
     }
 }
 
-async fn b64_encode_image(image_url: &str, max_dim: u32) -> anyhow::Result<String> {
-    let image_bytes = reqwest::get(image_url).await?.bytes().await?;
-    let img = ImageReader::new(Cursor::new(image_bytes))
-        .with_guessed_format()?
-        .decode()?;
+/// The roles of the author of the message.
+///
+/// The member data of the message is preferred, it is only missing in rare cases like messages
+/// fetched over http.
+pub fn sender_roles(message: &Message, cache: &InMemoryCache) -> Vec<Id<RoleMarker>> {
+    match &message.member {
+        Some(member) => member.roles.clone(),
+        None => message
+            .guild_id
+            .and_then(|guild_id| cache.member(guild_id, message.author.id))
+            .map(|member| member.roles().to_vec())
+            .unwrap_or_default(),
+    }
+}
+
+/// The time the author of the message joined the guild, if known.
+fn joined_at(message: &Message, cache: &InMemoryCache) -> Option<Timestamp> {
+    match &message.member {
+        Some(member) => member.joined_at,
+        None => message
+            .guild_id
+            .and_then(|guild_id| cache.member(guild_id, message.author.id))
+            .and_then(|member| member.joined_at()),
+    }
+}
+
+/// Whether the user is mentioned in the message.
+pub fn mentions_user(message: &Message, user_id: Id<UserMarker>) -> bool {
+    message.mentions.iter().any(|user| user.id == user_id)
+}
+
+/// Checks whether the channel is a thread with the parent channel `parent_id`.
+///
+/// This relies on the channel being cached.
+fn is_thread_of(
+    cache: &InMemoryCache,
+    channel_id: Id<ChannelMarker>,
+    parent_id: Id<ChannelMarker>,
+) -> bool {
+    cache
+        .channel(channel_id)
+        .is_some_and(|channel| channel.kind.is_thread() && channel.parent_id == Some(parent_id))
+}
 
-    // Make the image smaller while preserving the aspect ratio to save on tokens.
-    let img = if img.dimensions().0 > max_dim || img.dimensions().1 > max_dim {
-        img.resize(max_dim, max_dim, FilterType::Triangle)
-    } else {
-        img
+/// Whether the attachment is an image in one of the configured formats and small enough to be
+/// downloaded.
+fn is_supported_image(attachment: &Attachment, config: &super::Configuration) -> bool {
+    let Some((_, extension)) = attachment.filename.rsplit_once('.') else {
+        return false;
     };
+    if !config
+        .image_formats
+        .iter()
+        .any(|format| format.eq_ignore_ascii_case(extension))
+    {
+        return false;
+    }
+
+    if attachment.size > config.max_attachment_bytes {
+        debug!(
+            "Skipping image {} of {} bytes, it is larger than the maximum of {} bytes",
+            attachment.filename, attachment.size, config.max_attachment_bytes
+        );
+        return false;
+    }
+
+    true
+}
+
+/// The amount of times downloading an image is attempted.
+const IMAGE_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Whether the download failed because the link to the attachment expired.
+///
+/// Discord responds with 404 to expired links, and with 403 to links with an invalid signature.
+fn is_expired(err: &reqwest::Error) -> bool {
+    err.status().is_some_and(|status| {
+        status == reqwest::StatusCode::NOT_FOUND || status == reqwest::StatusCode::FORBIDDEN
+    })
+}
+
+/// Downloads the images sent to the LLM.
+///
+/// The http client is shared so connections are reused, and the permits are shared by all messages
+/// in a channel to limit the amount of images downloaded at the same time.
+#[derive(Debug, Clone)]
+pub struct ImageDownloader {
+    client: reqwest::Client,
+    /// Used to get new links to attachments when theirs expired.
+    http: Arc<Client>,
+    permits: Arc<Semaphore>,
+}
+
+/// Where an attachment was sent, so a new link to it can be requested.
+#[derive(Debug, Clone, Copy)]
+struct AttachmentSource {
+    channel_id: Id<ChannelMarker>,
+    message_id: Id<MessageMarker>,
+    attachment_id: Id<AttachmentMarker>,
+}
+
+impl ImageDownloader {
+    pub fn new(
+        http: Arc<Client>,
+        timeout: Duration,
+        max_concurrent_downloads: usize,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(timeout)
+                .build()
+                .context("failed to build the http client for images")?,
+            http,
+            permits: Arc::new(Semaphore::new(max_concurrent_downloads)),
+        })
+    }
+
+    /// The client files are downloaded with. It sends no credentials, so it can be used to download
+    /// files from any host.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
 
-    // Ensure the image is always in a color format supported in JPEG. Transparent pixels would
-    // otherwise fail to decode and make the bot ignore the image.
-    let img = img.into_rgb8();
+    /// Downloads the file, retrying with a backoff when it fails.
+    ///
+    /// Client errors, like a missing file, are not retried.
+    async fn download(&self, url: &str) -> reqwest::Result<Vec<u8>> {
+        let mut backoff = ExponentialBackoff::default();
+        let mut attempt = 1;
+        loop {
+            let result = async {
+                self.client
+                    .get(url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .bytes()
+                    .await
+                    .map(Vec::from)
+            }
+            .await;
 
-    let mut img_bytes = Vec::new();
-    img.write_to(&mut Cursor::new(&mut img_bytes), ImageFormat::Jpeg)?;
+            match result {
+                Ok(bytes) => return Ok(bytes),
+                Err(err)
+                    if attempt < IMAGE_DOWNLOAD_ATTEMPTS
+                        && !err.status().is_some_and(|status| status.is_client_error()) =>
+                {
+                    debug!("Failed to download image {url} (attempt {attempt}), retrying: {err}");
+                    sleep(backoff.next_backoff().unwrap_or_default()).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Requests a new link to the attachment, as the links to attachments expire after a while.
+    async fn refresh_url(&self, source: AttachmentSource) -> anyhow::Result<String> {
+        let message = self
+            .http
+            .message(source.channel_id, source.message_id)
+            .await?
+            .model()
+            .await?;
+        message
+            .attachments
+            .into_iter()
+            .find(|attachment| attachment.id == source.attachment_id)
+            .map(|attachment| attachment.url)
+            .context("the attachment was removed from the message")
+    }
+
+    /// Downloads the image and encodes it as a base64 JPEG, resized to fit in `max_dim` using
+    /// `filter`.
+    ///
+    /// When the link to the image expired, the image is downloaded again using a new link.
+    async fn b64_encode_image(
+        &self,
+        image_url: &str,
+        source: AttachmentSource,
+        max_dim: u32,
+        filter: FilterType,
+    ) -> anyhow::Result<String> {
+        let image_bytes = {
+            let _permit = self.permits.acquire().await?;
+            match self.download(image_url).await {
+                Ok(bytes) => bytes,
+                Err(err) if is_expired(&err) => {
+                    debug!("The link to image {image_url} may have expired, refreshing it: {err}");
+                    let url = self
+                        .refresh_url(source)
+                        .await
+                        .context("failed to refresh the link to the image")?;
+                    self.download(&url).await?
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+        let reader = ImageReader::new(Cursor::new(&image_bytes[..])).with_guessed_format()?;
+        let img = if reader.format() == Some(ImageFormat::Gif) {
+            // Only the first frame of animated images is used.
+            let frame = GifDecoder::new(Cursor::new(&image_bytes[..]))?
+                .into_frames()
+                .next()
+                .context("GIF does not contain any frames")??;
+            DynamicImage::ImageRgba8(frame.into_buffer())
+        } else {
+            reader.decode()?
+        };
+
+        // Make the image smaller while preserving the aspect ratio to save on tokens.
+        let img = if img.dimensions().0 > max_dim || img.dimensions().1 > max_dim {
+            img.resize(max_dim, max_dim, filter)
+        } else {
+            img
+        };
+
+        // Ensure the image is always in a color format supported in JPEG. Transparent pixels would
+        // otherwise fail to decode and make the bot ignore the image.
+        let img = img.into_rgb8();
+
+        let mut img_bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut img_bytes), ImageFormat::Jpeg)?;
+
+        Ok(BASE64_STANDARD.encode(img_bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_placeholders_replaced() {
+        let rendered =
+            render_template(
+                "[{author_name}] {content} {unknown}",
+                |placeholder| match placeholder {
+                    "author_name" => Some("bob".to_string()),
+                    "content" => Some("hi {author_name}".to_string()),
+                    _ => None,
+                },
+            );
+
+        assert_eq!(rendered, "[bob] hi {author_name} {unknown}");
+    }
+
+    #[test]
+    fn template_validated() {
+        assert!(validate_template("{author_name}: {content}").is_ok());
+        assert!(validate_template("{author_name}").is_err());
+        assert!(validate_template("{author}: {content}").is_err());
+    }
+
+    fn message(display_name: Option<&str>) -> UserMessage {
+        UserMessage {
+            message_id: Id::new(1),
+            merged_ids: Vec::new(),
+            channel_id: Id::new(2),
+            guild_id: None,
+            reply_to: None,
+            content: "hello".to_string(),
+            sender_name: "alice_1990".to_string(),
+            sender_display_name: display_name.map(str::to_string),
+            sender_id: Id::new(123456789),
+            sender_roles: Vec::new(),
+            sent_at: Timestamp::from_secs(0).unwrap(),
+            attachments: Vec::new(),
+            mentions_bot: false,
+            sender_pseudonym: None,
+        }
+    }
 
-    Ok(BASE64_STANDARD.encode(img_bytes))
+    #[test]
+    fn format_with_usernames() {
+        let formatted = message(Some("Alice")).format_message(None, true);
+        assert!(formatted.contains("author_name: alice_1990 (Alice)\nauthor_id: 123456789\n"));
+    }
+
+    #[test]
+    fn format_without_usernames() {
+        let formatted = message(Some("Alice")).format_message(None, false);
+        assert!(formatted.contains("author: Alice\n"));
+        assert!(!formatted.contains("alice_1990"));
+        assert!(!formatted.contains("123456789"));
+
+        let anonymized = UserMessage {
+            sender_pseudonym: Some("user_1".to_string()),
+            ..message(None)
+        };
+        let formatted =
+            anonymized.format_message(Some("{author_name} {author_id}: {content}"), false);
+        assert_eq!(formatted, "user_1 user_1: hello");
+    }
+
+    fn message_at(message_id: u64, sender_id: u64, sent_at_secs: i64) -> UserMessage {
+        UserMessage {
+            message_id: Id::new(message_id),
+            sender_id: Id::new(sender_id),
+            content: format!("message {message_id}"),
+            sent_at: Timestamp::from_secs(sent_at_secs).unwrap(),
+            ..message(None)
+        }
+    }
+
+    #[test]
+    fn consecutive_messages_merged() {
+        let window = Duration::from_secs(30);
+        let mut merged = message_at(1, 10, 0);
+        assert!(merged.merge(message_at(2, 10, 20), window).is_ok());
+        // The window starts at the most recent merged message.
+        assert!(merged.merge(message_at(3, 10, 45), window).is_ok());
+
+        assert_eq!(merged.content, "message 1\nmessage 2\nmessage 3");
+        assert_eq!(merged.message_id, Id::new(3));
+        assert!(
+            merged
+                .format_message(None, true)
+                .starts_with("<msg>message_id: 1, 2, 3\n")
+        );
+    }
+
+    #[test]
+    fn unrelated_messages_not_merged() {
+        let window = Duration::from_secs(30);
+        let mut first = message_at(1, 10, 0);
+        assert!(first.merge(message_at(2, 11, 5), window).is_err());
+        assert!(first.merge(message_at(3, 10, 60), window).is_err());
+
+        let reply = UserMessage {
+            reply_to: Some(Id::new(1)),
+            ..message_at(4, 10, 5)
+        };
+        assert!(first.merge(reply, window).is_err());
+        assert_eq!(first.content, "message 1");
+        assert!(first.merged_ids.is_empty());
+    }
 }
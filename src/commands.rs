@@ -0,0 +1,327 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, error, info};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::Event;
+use twilight_http::{Client, request::channel::reaction::RequestReactionType};
+use twilight_model::{
+    application::{
+        command::{Command, CommandType},
+        interaction::{Interaction, InteractionContextType, InteractionData},
+    },
+    channel::{Channel, Message, message::MessageFlags},
+    guild::Permissions,
+    http::interaction::{InteractionResponse, InteractionResponseType},
+    id::{
+        Id,
+        marker::{ChannelMarker, UserMarker},
+    },
+};
+use twilight_util::builder::{InteractionResponseDataBuilder, command::CommandBuilder};
+
+use crate::ai_channel::Shared;
+
+/// Checks if the bot is connected.
+pub const PING: &str = "ping";
+/// Clears the conversation history of an AI channel or one of its threads.
+pub const RESET: &str = "reset";
+/// Stops every AI channel from responding until it is resumed, only available as a message.
+pub const PAUSE: &str = "pause";
+/// Lets the AI channels respond again after being paused, only available as a message.
+pub const RESUME: &str = "resume";
+
+/// The application commands of the bot.
+fn commands() -> Vec<Command> {
+    vec![
+        CommandBuilder::new(PING, "Check if the bot is online", CommandType::ChatInput).build(),
+        CommandBuilder::new(
+            RESET,
+            "Make the AI forget the conversation in this channel",
+            CommandType::ChatInput,
+        )
+        .contexts([InteractionContextType::Guild])
+        .build(),
+    ]
+}
+
+/// Registers the application commands and responds to them.
+///
+/// `ai_channels` contains the id of every running AI channel and whether its threads are included,
+/// this is used to tell if a command was used in an AI channel. The AI channels handle and answer
+/// the reset themselves, only resets used outside of AI channels are answered here.
+///
+/// Pause and resume commands sent as messages in any channel set `paused` right away, so the AI
+/// channels stop sending requests without having to handle the command first.
+pub async fn serve(
+    mut events: broadcast::Receiver<Arc<Event>>,
+    http: Arc<Client>,
+    cache: Arc<InMemoryCache>,
+    ai_channels: watch::Receiver<Vec<(Id<ChannelMarker>, bool)>>,
+    shared: watch::Receiver<Arc<Shared>>,
+    paused: Arc<AtomicBool>,
+) {
+    // Every shard sends a ready event, the commands only have to be registered once.
+    let mut registered = false;
+
+    loop {
+        let event = events.recv().await;
+        let interaction = match event.as_deref() {
+            Err(broadcast::error::RecvError::Closed) => return,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                crate::log_lagged_events("commands", skipped);
+                continue;
+            }
+            Ok(Event::Ready(ready)) if !registered => {
+                registered = true;
+                match http
+                    .interaction(ready.application.id)
+                    .set_global_commands(&commands())
+                    .await
+                {
+                    Ok(_) => info!("Registered application commands"),
+                    Err(err) => error!("Failed to register application commands: {err}"),
+                }
+                continue;
+            }
+            Ok(Event::InteractionCreate(interaction)) => interaction,
+            Ok(Event::MessageCreate(message)) => {
+                let shared = shared.borrow().clone();
+                pause_on_command(&http, &cache, &shared, &paused, message).await;
+                continue;
+            }
+            Ok(_) => continue,
+        };
+
+        let Some(InteractionData::ApplicationCommand(data)) = &interaction.data else {
+            continue;
+        };
+
+        let content = match data.name.as_str() {
+            PING => "Pong!",
+            RESET => {
+                let in_ai_channel = interaction.channel.as_ref().is_some_and(|channel| {
+                    ai_channels
+                        .borrow()
+                        .iter()
+                        .any(|(channel_id, include_threads)| {
+                            is_in_channel(channel, *channel_id, *include_threads)
+                        })
+                });
+                if in_ai_channel {
+                    continue;
+                }
+                "This command can only be used in AI channels."
+            }
+            _ => continue,
+        };
+
+        respond(&http, interaction, content).await;
+    }
+}
+
+/// Pauses or resumes the AI channels when the message is a pause or resume command from an
+/// administrator or owner, and reacts to the message once applied.
+async fn pause_on_command(
+    http: &Client,
+    cache: &InMemoryCache,
+    shared: &Shared,
+    paused: &AtomicBool,
+    message: &Message,
+) {
+    if message.author.bot {
+        return;
+    }
+    let allowed = || {
+        shared.is_owner(message.author.id)
+            || has_permissions(
+                cache,
+                message.author.id,
+                message.channel_id,
+                Permissions::ADMINISTRATOR,
+            )
+    };
+    let Some(pause) =
+        apply_pause_command(&message.content, &shared.command_prefix, allowed, paused)
+    else {
+        return;
+    };
+
+    let (emoji, state) = if pause {
+        ("⏸️", "Paused")
+    } else {
+        ("▶️", "Resumed")
+    };
+    info!(
+        "{state} all AI channels, requested by message {}",
+        message.id
+    );
+    let reaction = RequestReactionType::Unicode { name: emoji };
+    if let Err(err) = http
+        .create_reaction(message.channel_id, message.id, &reaction)
+        .await
+    {
+        error!(
+            "Failed to confirm the {} command: {err}",
+            state.to_lowercase()
+        );
+    }
+}
+
+/// Sets `paused` when `content` is a pause or resume command and the author is `allowed` to use
+/// it. Returns whether the AI channels are now paused, or `None` when nothing changed.
+fn apply_pause_command(
+    content: &str,
+    prefix: &str,
+    allowed: impl FnOnce() -> bool,
+    paused: &AtomicBool,
+) -> Option<bool> {
+    let (command @ (PAUSE | RESUME), _) = parse_command(content, prefix)? else {
+        return None;
+    };
+    if !allowed() {
+        debug!("Ignoring {command} command, the author is not an administrator");
+        return None;
+    }
+
+    let pause = command == PAUSE;
+    paused.store(pause, Ordering::Relaxed);
+    Some(pause)
+}
+
+/// Sends a response only visible to the user that used the command.
+pub async fn respond(http: &Client, interaction: &Interaction, content: &str) {
+    let response = InteractionResponse {
+        kind: InteractionResponseType::ChannelMessageWithSource,
+        data: Some(
+            InteractionResponseDataBuilder::new()
+                .content(content)
+                .flags(MessageFlags::EPHEMERAL)
+                .build(),
+        ),
+    };
+
+    if let Err(err) = http
+        .interaction(interaction.application_id)
+        .create_response(interaction.id, &interaction.token, &response)
+        .await
+    {
+        error!("Failed to respond to interaction: {err}");
+    }
+}
+
+/// Returns the channel the command with the given name was used in.
+pub fn command_channel<'a>(interaction: &'a Interaction, name: &str) -> Option<&'a Channel> {
+    match &interaction.data {
+        Some(InteractionData::ApplicationCommand(data)) if data.name == name => {
+            interaction.channel.as_ref()
+        }
+        _ => None,
+    }
+}
+
+/// Whether `channel` is the channel with `channel_id`, or one of its threads when `include_threads`
+/// is set.
+pub fn is_in_channel(
+    channel: &Channel,
+    channel_id: Id<ChannelMarker>,
+    include_threads: bool,
+) -> bool {
+    channel.id == channel_id
+        || (include_threads && channel.kind.is_thread() && channel.parent_id == Some(channel_id))
+}
+
+/// Splits a command sent as a message into the command name and its arguments.
+///
+/// Returns `None` when the message does not start with `prefix` directly followed by a name. The
+/// arguments are trimmed and empty when none are given.
+pub fn parse_command<'a>(content: &'a str, prefix: &str) -> Option<(&'a str, &'a str)> {
+    let command = content.trim_start().strip_prefix(prefix)?;
+    let (name, args) = command
+        .split_once(char::is_whitespace)
+        .unwrap_or((command, ""));
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, args.trim()))
+}
+
+/// Whether the user that used the command has all `permissions` in the channel it was used in.
+///
+/// The permissions are checked when the command is used instead of setting default permissions of
+/// the command, as those would hide the command from owners without the permissions.
+pub fn interaction_has_permissions(interaction: &Interaction, permissions: Permissions) -> bool {
+    interaction
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|granted| granted.contains(permissions))
+}
+
+/// Whether the user has all `permissions` in the channel.
+///
+/// Commands sent as messages don't get the permission checks of application commands, so they are
+/// checked using the cache instead. Returns false when the permissions can't be calculated.
+pub fn has_permissions(
+    cache: &InMemoryCache,
+    user_id: Id<UserMarker>,
+    channel_id: Id<ChannelMarker>,
+    permissions: Permissions,
+) -> bool {
+    cache
+        .permissions()
+        .in_channel(user_id, channel_id)
+        .is_ok_and(|granted| granted.contains(permissions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_name_and_args() {
+        assert_eq!(parse_command("!reset", "!"), Some(("reset", "")));
+        assert_eq!(
+            parse_command("!remind  me later ", "!"),
+            Some(("remind", "me later"))
+        );
+        assert_eq!(parse_command("bot:reset", "bot:"), Some(("reset", "")));
+    }
+
+    #[test]
+    fn pause_sets_flag() {
+        let paused = AtomicBool::new(false);
+
+        assert_eq!(
+            apply_pause_command("!pause", "!", || true, &paused),
+            Some(true)
+        );
+        assert!(paused.load(Ordering::Relaxed));
+
+        assert_eq!(
+            apply_pause_command("!resume", "!", || true, &paused),
+            Some(false)
+        );
+        assert!(!paused.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn pause_not_allowed() {
+        let paused = AtomicBool::new(false);
+
+        assert_eq!(apply_pause_command("!pause", "!", || false, &paused), None);
+        assert_eq!(apply_pause_command("!reset", "!", || true, &paused), None);
+        assert!(!paused.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn parse_not_a_command() {
+        assert_eq!(parse_command("reset", "!"), None);
+        assert_eq!(parse_command("!", "!"), None);
+        assert_eq!(parse_command("! reset", "!"), None);
+        assert_eq!(parse_command("hi !reset", "!"), None);
+    }
+}
@@ -1,30 +1,96 @@
 use std::{
+    collections::{HashMap, HashSet},
     env,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::Context;
-use serde::Deserialize;
+use config::FileFormat;
+use serde::{Deserialize, Serialize, Serializer};
+use tracing::{info, warn};
+use twilight_cache_inmemory::ResourceType;
+use twilight_gateway::Intents;
+use twilight_model::id::{
+    Id,
+    marker::{ChannelMarker, GuildMarker, UserMarker},
+};
 
-use crate::ai_channel;
+use crate::{ai_channel, error, health, presence, prometheus};
 pub(crate) mod file_watch;
 
-#[derive(Debug, Deserialize)]
+/// The files the configuration is read from when no locations are specified, only the first one
+/// that exists is used.
+///
+/// The format of each file is determined by its extension.
+pub const DEFAULT_LOCATIONS: [&str; 4] = ["bot.toml", "bot.yaml", "bot.yml", "bot.json"];
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Configuration {
     /// The bot's discord token.
+    #[serde(default, serialize_with = "redact")]
     pub token: String,
+    /// A file containing the bot's discord token, this can be used instead of `token`.
+    token_file: Option<PathBuf>,
+    /// The amount of shards to start. When not set, the amount recommended by discord is used.
+    ///
+    /// Discord requires bots to use multiple shards once they are in more than 2500 guilds.
+    pub shard_count: Option<u32>,
+    /// The amount of events that are buffered for each feature that handles events.
+    ///
+    /// Features that fall behind by more than this skip the oldest events.
+    #[serde(default = "default_event_capacity")]
+    pub event_capacity: usize,
+    /// The prefix of commands sent as messages, like `!reset`.
+    #[serde(default = "default_command_prefix")]
+    pub command_prefix: String,
+    /// The users that may use every command, regardless of their permissions.
+    #[serde(default)]
+    pub owners: Vec<Id<UserMarker>>,
+    /// The status and activity shown for the bot.
+    pub presence: Option<presence::Configuration>,
+    /// When set, a http server is started with health check endpoints for container orchestration.
+    pub health: Option<health::Configuration>,
+    /// When set, metrics are recorded and served in the prometheus format.
+    pub metrics: Option<prometheus::Configuration>,
+    /// How errors sent in discord look.
+    #[serde(default)]
+    pub errors: error::ErrorStyle,
+    /// When set, errors from every channel are also sent in this channel.
+    pub error_log_channel_id: Option<Id<ChannelMarker>>,
     #[serde(default, rename = "ai_channel")]
     pub ai_channels: Vec<ai_channel::Configuration>,
+    /// Settings that are overridden for specific guilds.
+    #[serde(default, rename = "guild")]
+    pub guilds: Vec<GuildConfiguration>,
+}
+
+/// Overrides the settings of features for events in a single guild.
+///
+/// A setting set for the guild takes precedence, otherwise the global setting is used.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct GuildConfiguration {
+    pub guild_id: Id<GuildMarker>,
+    /// Overrides for all AI channels in the guild.
+    #[serde(default)]
+    pub ai_channel: ai_channel::GuildOverrides,
 }
 
 impl Configuration {
     /// Read the configuration from the specified location.
     ///
     /// Each path is a layer: values set in later entries override the values set by earlier ones.
-    pub fn read<'a>(locations: impl IntoIterator<Item = &'a Path>) -> anyhow::Result<Self> {
+    /// The format of each file (toml, yaml or json) is determined by its extension.
+    ///
+    /// Locations starting with `http://` or `https://` are fetched once, they are not watched for
+    /// changes. Unlike files, which are skipped when they don't exist, they must be available.
+    pub async fn read<'a>(locations: impl IntoIterator<Item = &'a Path>) -> anyhow::Result<Self> {
         let mut settings = config::Config::builder();
         for location in locations {
-            settings = settings.add_source(config::File::from(location).required(false));
+            settings = match remote_url(location) {
+                Some(url) => settings.add_source(fetch_remote(url).await?),
+                None => settings.add_source(config::File::from(location).required(false)),
+            };
         }
 
         let config = settings
@@ -32,36 +98,277 @@ impl Configuration {
             .build()
             .context("failed to build config")?;
 
-        config
+        let mut config: Self = config
             .try_deserialize()
-            .context("failed to deserialize config")
+            .context("failed to deserialize config")?;
+        config.read_secrets()?;
+
+        Ok(config)
     }
 
-    /// Reads the configuration from the locations specified in the environment variable. The paths
-    /// in `default` are used if the variable is not set.
+    /// Reads the secrets that are stored in separate files into their corresponding fields.
+    fn read_secrets(&mut self) -> anyhow::Result<()> {
+        read_secret(&mut self.token, self.token_file.as_deref(), "token")?;
+        for channel in &mut self.ai_channels {
+            channel.read_secrets()?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the configuration of every enabled feature.
     ///
-    /// See also: [Configuration::read].
-    pub fn read_with_env<'a>(
-        env_var: &str,
-        default: impl IntoIterator<Item = &'a Path>,
-    ) -> anyhow::Result<Self> {
-        match env::var(env_var) {
-            Ok(paths) => {
-                let paths = paths.split(',').map(PathBuf::from).collect::<Vec<_>>();
-                Self::read(paths.iter().map(|p| p.as_path()))
-            }
-            Err(_) => Self::read(default),
+    /// This should be called at startup so mistakes are reported before connecting to discord,
+    /// rather than while handling events.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.token.is_empty(), "`token` must not be empty");
+        anyhow::ensure!(
+            self.shard_count != Some(0),
+            "`shard_count` must be at least 1"
+        );
+        anyhow::ensure!(
+            self.event_capacity > 0,
+            "`event_capacity` must be at least 1"
+        );
+        anyhow::ensure!(
+            !self.command_prefix.is_empty()
+                && !self.command_prefix.chars().any(char::is_whitespace),
+            "`command_prefix` must not be empty or contain whitespace"
+        );
+
+        if let Some(presence) = &self.presence {
+            presence
+                .payload()
+                .context("invalid presence configuration")?;
         }
+
+        self.errors
+            .validate()
+            .context("invalid errors configuration")?;
+
+        for channel in &self.ai_channels {
+            channel.validate().with_context(|| {
+                format!(
+                    "invalid configuration for AI channel {}",
+                    channel.get_channel_id()
+                )
+            })?;
+        }
+
+        let mut channel_ids = HashSet::new();
+        for channel in &self.ai_channels {
+            anyhow::ensure!(
+                channel_ids.insert(channel.get_channel_id()),
+                "AI channel {} is configured more than once",
+                channel.get_channel_id()
+            );
+        }
+
+        let mut guild_ids = HashSet::new();
+        for guild in &self.guilds {
+            anyhow::ensure!(
+                guild_ids.insert(guild.guild_id),
+                "guild {} is configured more than once",
+                guild.guild_id
+            );
+            guild.ai_channel.validate().with_context(|| {
+                format!("invalid AI channel overrides for guild {}", guild.guild_id)
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Logs which features are enabled and their most important settings, so it can be checked at a
+    /// glance that the configuration took effect.
+    pub fn log_summary(&self) {
+        info!(
+            shard_count = ?self.shard_count,
+            presence = self.presence.is_some(),
+            health = self.health.is_some(),
+            metrics = self.metrics.is_some(),
+            error_log_channel_id = ?self.error_log_channel_id,
+            command_prefix = %self.command_prefix,
+            owners = self.owners.len(),
+            ai_channels = self.ai_channels.len(),
+            guild_overrides = self.guilds.len(),
+            "Enabled features"
+        );
+        for channel in &self.ai_channels {
+            channel.log_summary();
+        }
+    }
+
+    /// The gateway intents needed by all enabled features.
+    ///
+    /// Privileged intents included in this set must also be enabled in the discord developer portal.
+    pub fn intents(&self) -> Intents {
+        self.ai_channels
+            .iter()
+            .fold(Intents::empty(), |acc, channel| acc | channel.intents())
+    }
+
+    /// The resource types the in-memory cache needs to store for all enabled features.
+    ///
+    /// Only the resources that are actually used are cached to keep memory usage low.
+    pub fn resource_types(&self) -> ResourceType {
+        self.ai_channels
+            .iter()
+            .fold(ResourceType::empty(), |acc, channel| {
+                acc | channel.resource_types()
+            })
+    }
+
+    /// Serializes the configuration as it was loaded, with the secrets replaced by `***`.
+    ///
+    /// This shows the result of all layered files and environment variables, which helps with
+    /// debugging deployments.
+    pub fn to_redacted_toml(&self) -> anyhow::Result<String> {
+        toml::to_string_pretty(self).context("failed to serialize config")
+    }
+}
+
+/// The configuration locations specified in the environment variable as a comma separated list, or
+/// the first of the `default` files that exists if the variable is not set.
+///
+/// The default files are not layered, so a leftover file in another format isn't silently merged
+/// into the configuration. A warning is logged when more than one of them exists. When none of them
+/// exist, the first one is returned so it is read once it is created.
+///
+/// See also: [Configuration::read].
+pub fn locations<'a>(env_var: &str, default: impl IntoIterator<Item = &'a Path>) -> Vec<PathBuf> {
+    if let Ok(paths) = env::var(env_var) {
+        return paths.split(',').map(PathBuf::from).collect();
+    }
+
+    let default: Vec<&Path> = default.into_iter().collect();
+    let mut existing = default.iter().filter(|path| path.exists());
+    let Some(location) = existing.next().or(default.first()) else {
+        return Vec::new();
+    };
+    let ignored: Vec<_> = existing
+        .map(|path| format!("'{}'", path.display()))
+        .collect();
+    if !ignored.is_empty() {
+        warn!(
+            "Reading the configuration from '{}', ignoring {}. Set `{env_var}` to combine multiple \
+             files",
+            location.display(),
+            ignored.join(", ")
+        );
+    }
+
+    vec![location.to_path_buf()]
+}
+
+/// The maximum time fetching a remote configuration may take.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Returns the location as url if it is a remote location.
+pub fn remote_url(location: &Path) -> Option<&str> {
+    location
+        .to_str()
+        .filter(|location| location.starts_with("http://") || location.starts_with("https://"))
+}
+
+/// Fetches a configuration file over http.
+async fn fetch_remote(
+    url: &str,
+) -> anyhow::Result<config::File<config::FileSourceString, FileFormat>> {
+    let format = remote_format(url)?;
+    let contents = reqwest::Client::builder()
+        .timeout(REMOTE_TIMEOUT)
+        .build()
+        .context("failed to create http client")?
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("failed to fetch config from '{url}'"))?
+        .text()
+        .await
+        .with_context(|| format!("failed to read config from '{url}'"))?;
+
+    Ok(config::File::from_str(&contents, format))
+}
+
+/// The format of a remote configuration file, determined by the extension in the url.
+fn remote_format(url: &str) -> anyhow::Result<FileFormat> {
+    let parsed = reqwest::Url::parse(url).with_context(|| format!("invalid config url '{url}'"))?;
+    let extension = Path::new(parsed.path())
+        .extension()
+        .and_then(|extension| extension.to_str());
+    match extension {
+        Some("toml") => Ok(FileFormat::Toml),
+        Some("yaml" | "yml") => Ok(FileFormat::Yaml),
+        Some("json") => Ok(FileFormat::Json),
+        _ => anyhow::bail!(
+            "unknown format of config url '{url}', it must end in .toml, .yaml, .yml or .json"
+        ),
+    }
+}
+
+fn default_event_capacity() -> usize {
+    16
+}
+
+fn default_command_prefix() -> String {
+    "!".to_string()
+}
+
+/// Serializes a secret as `***`, so it isn't shown when the configuration is printed.
+///
+/// Empty secrets are kept empty, to show that they are not set.
+pub fn redact<S: Serializer>(secret: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    if secret.is_empty() {
+        serializer.serialize_str("")
+    } else {
+        serializer.serialize_str("***")
     }
 }
 
+/// Serializes the values of a map, like http headers, as `***` as they may contain secrets.
+pub fn redact_values<S: Serializer>(
+    map: &HashMap<String, String>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(map.keys().map(|key| (key, "***")))
+}
+
+/// Reads a secret from `file` into `value` if the file is set.
+///
+/// `name` is the name of the field containing the secret, the file is expected to be set using the
+/// `{name}_file` field.
+pub fn read_secret(value: &mut String, file: Option<&Path>, name: &str) -> anyhow::Result<()> {
+    let Some(file) = file else {
+        return Ok(());
+    };
+
+    anyhow::ensure!(
+        value.is_empty(),
+        "both `{name}` and `{name}_file` are set, only one of them may be used"
+    );
+
+    let secret = std::fs::read_to_string(file)
+        .with_context(|| format!("failed to read `{name}_file` at '{}'", file.display()))?;
+    let secret = secret.trim();
+    anyhow::ensure!(
+        !secret.is_empty(),
+        "`{name}_file` at '{}' is empty",
+        file.display()
+    );
+
+    *value = secret.to_string();
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     /// Ensures that the example config file is valid.
-    #[test]
-    fn example_config_valid() {
+    #[tokio::test]
+    async fn example_config_valid() {
         // Using a temp dir to avoid have to create a build script to copy the example toml into ./target
         let temp_dir = tempfile::tempdir().expect("Unable to create tempoary directory");
 
@@ -70,7 +377,230 @@ mod tests {
         std::fs::write(&example_toml, include_str!("../example_bot.toml"))
             .expect("Unable to write example data to temp file");
 
-        Configuration::read([example_toml.as_path()])
+        let config = Configuration::read([example_toml.as_path()])
+            .await
             .expect("Unable to parse example configuration file.");
+        config
+            .validate()
+            .expect("Example configuration file is not valid.");
+    }
+
+    /// Reads a configuration from a toml string.
+    async fn read_toml(toml: &str) -> anyhow::Result<Configuration> {
+        read_file("bot.toml", toml).await
+    }
+
+    /// Writes the contents to a temporary file with the given name and reads it as configuration.
+    async fn read_file(file_name: &str, contents: &str) -> anyhow::Result<Configuration> {
+        let temp_dir = tempfile::tempdir().expect("Unable to create tempoary directory");
+
+        let mut path = temp_dir.path().to_path_buf();
+        path.push(file_name);
+        std::fs::write(&path, contents).expect("Unable to write config to temp file");
+
+        Configuration::read([path.as_path()]).await
+    }
+
+    /// The same configuration must be read the same way regardless of the file format.
+    #[tokio::test]
+    async fn formats_equivalent() {
+        let toml = read_file(
+            "bot.toml",
+            r#"
+            token = "token"
+
+            [[ai_channel]]
+            channel_id = 1346872485395828902
+            llm_api_key = "key"
+            model_name = "model"
+            prompt_path = "prompt.txt"
+            max_history_size = 10
+            "#,
+        )
+        .await
+        .expect("Unable to parse toml configuration");
+
+        let yaml = read_file(
+            "bot.yaml",
+            r#"
+token: token
+ai_channel:
+  - channel_id: 1346872485395828902
+    llm_api_key: key
+    model_name: model
+    prompt_path: prompt.txt
+    max_history_size: 10
+"#,
+        )
+        .await
+        .expect("Unable to parse yaml configuration");
+
+        let json = read_file(
+            "bot.json",
+            r#"{
+                "token": "token",
+                "ai_channel": [{
+                    "channel_id": 1346872485395828902,
+                    "llm_api_key": "key",
+                    "model_name": "model",
+                    "prompt_path": "prompt.txt",
+                    "max_history_size": 10
+                }]
+            }"#,
+        )
+        .await
+        .expect("Unable to parse json configuration");
+
+        assert_eq!(toml, yaml);
+        assert_eq!(toml, json);
+    }
+
+    /// Secrets set using a `*_file` field must be read from that file without surrounding whitespace.
+    #[tokio::test]
+    async fn token_read_from_file() {
+        let temp_dir = tempfile::tempdir().expect("Unable to create tempoary directory");
+
+        let mut token_file = temp_dir.path().to_path_buf();
+        token_file.push("token");
+        std::fs::write(&token_file, "secret token\n").expect("Unable to write token file");
+
+        let config = read_toml(&format!(
+            "token_file = {:?}",
+            token_file.display().to_string()
+        ))
+        .await
+        .expect("Unable to parse configuration");
+
+        assert_eq!(config.token, "secret token");
+    }
+
+    /// Referencing a secret file that does not exist must be reported as an error.
+    #[tokio::test]
+    async fn missing_secret_file_invalid() {
+        assert!(
+            read_toml(r#"token_file = "/this/file/does/not/exist""#)
+                .await
+                .is_err()
+        );
+    }
+
+    /// A channel keeping more history than it is allowed to have must be rejected.
+    #[tokio::test]
+    async fn min_history_larger_than_max_invalid() {
+        let config = read_toml(
+            r#"
+            token = "token"
+
+            [[ai_channel]]
+            channel_id = 1
+            llm_api_key = ""
+            model_name = "model"
+            prompt_path = "prompt.txt"
+            max_history_size = 10
+            min_history_size = 20
+            "#,
+        )
+        .await
+        .expect("Unable to parse configuration");
+
+        assert!(config.validate().is_err());
+    }
+
+    /// A header named `X Api Org` must be rejected, header names can't contain spaces.
+    #[tokio::test]
+    async fn invalid_extra_header_invalid() {
+        let config = read_toml(
+            r#"
+            token = "token"
+
+            [[ai_channel]]
+            channel_id = 1
+            llm_api_key = ""
+            model_name = "model"
+            prompt_path = "prompt.txt"
+            extra_headers = { "X Api Org" = "org" }
+            "#,
+        )
+        .await
+        .expect("Unable to parse configuration");
+
+        assert!(config.validate().is_err());
+    }
+
+    /// Events can't be handled without buffering at least one of them.
+    #[tokio::test]
+    async fn zero_event_capacity_invalid() {
+        let config = read_toml(
+            r#"
+            token = "token"
+            event_capacity = 0
+            "#,
+        )
+        .await
+        .expect("Unable to parse configuration");
+
+        assert!(config.validate().is_err());
+    }
+
+    /// Printing the configuration must not leak the secrets.
+    #[tokio::test]
+    async fn printed_config_redacted() {
+        let config = read_toml(
+            r#"
+            token = "secret-token"
+
+            [[ai_channel]]
+            channel_id = 1
+            llm_api_key = "secret-key"
+            model_name = "model"
+            prompt_path = "prompt.txt"
+            extra_headers = { Authorization = "Bearer secret-header" }
+            "#,
+        )
+        .await
+        .expect("Unable to parse configuration");
+
+        let printed = config
+            .to_redacted_toml()
+            .expect("Unable to print configuration");
+        assert!(!printed.contains("secret-token"));
+        assert!(!printed.contains("secret-key"));
+        assert!(!printed.contains("secret-header"));
+        assert!(printed.contains(r#"token = "***""#));
+        assert!(printed.contains(r#"llm_api_key = "***""#));
+        assert!(printed.contains(r#"model_name = "model""#));
+    }
+
+    /// Only the first default file that exists must be read, the others are ignored.
+    #[test]
+    fn first_existing_default_location() {
+        let temp_dir = tempfile::tempdir().expect("Unable to create tempoary directory");
+        let default = ["bot.toml", "bot.yaml", "bot.json"].map(|name| temp_dir.path().join(name));
+        let env_var = "BOT_TEST_UNSET_CONFIG_PATH";
+
+        assert_eq!(
+            locations(env_var, default.iter().map(PathBuf::as_path)),
+            [default[0].clone()]
+        );
+
+        std::fs::write(&default[2], "{}").expect("Unable to write config file");
+        std::fs::write(&default[1], "").expect("Unable to write config file");
+        assert_eq!(
+            locations(env_var, default.iter().map(PathBuf::as_path)),
+            [default[1].clone()]
+        );
+    }
+
+    #[test]
+    fn remote_format_from_extension() {
+        assert_eq!(
+            remote_format("https://example.com/bot.toml").unwrap(),
+            FileFormat::Toml
+        );
+        assert_eq!(
+            remote_format("https://example.com/config/bot.yml?token=abc").unwrap(),
+            FileFormat::Yaml
+        );
+        assert!(remote_format("https://example.com/config").is_err());
     }
 }
@@ -1,35 +1,85 @@
-use anyhow::anyhow;
+use anyhow::{Context, anyhow};
 use notify::{Config, Event, RecommendedWatcher, Watcher};
-use std::{fs::File, path::Path};
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 use tokio::sync::watch;
 
-/// Reads the channel prompt into a [`watch`] channel.
+/// Placed between the contents of the prompt files when a prompt consists of multiple files.
+pub const PROMPT_SEPARATOR: &str = "\n\n";
+
+/// Reads the prompt files into a single prompt in a [`watch`] channel.
+///
+/// The [`watch::Receiver`] will have its value updated by [`monitor_prompts`] when one of the
+/// files is modified.
 ///
-/// The [`watch::Receiver`] will have its value updated when the channel prompt file is modified.
+/// The files are joined in order with [`PROMPT_SEPARATOR`], so later files are appended after
+/// earlier ones. Files larger than `max_bytes` are not read, so a path pointing at the wrong file
+/// doesn't become the prompt.
 #[doc(alias = "read_prompt")]
-pub async fn load_prompt(
-    prompt_path: &Path,
-) -> Result<(watch::Sender<Box<str>>, watch::Receiver<Box<str>>), std::io::Error> {
-    let current_prompt = tokio::fs::read_to_string(&prompt_path)
-        .await?
-        .into_boxed_str();
-
-    Ok(watch::channel(current_prompt))
+pub async fn load_prompts(
+    prompt_paths: &[&Path],
+    max_bytes: u64,
+) -> anyhow::Result<(watch::Sender<Box<str>>, watch::Receiver<Box<str>>)> {
+    let mut contents = Vec::with_capacity(prompt_paths.len());
+    for path in prompt_paths {
+        let size = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("Unable to read prompt file at '{}'", path.display()))?
+            .len();
+        check_prompt_size(path, size, max_bytes)?;
+        let bytes = tokio::fs::read(path)
+            .await
+            .with_context(|| format!("Unable to read prompt file at '{}'", path.display()))?;
+        contents.push(decode_prompt(path, bytes)?);
+    }
+
+    Ok(watch::channel(
+        contents.join(PROMPT_SEPARATOR).into_boxed_str(),
+    ))
 }
 
-/// Monitors the channel prompt file for changes.
+/// Monitors the prompt files for changes, the prompt is rebuilt when any of them changes.
+///
+/// See [`load_prompts`] for how the files are combined. Changed files larger than `max_bytes` are
+/// ignored, the previous contents are kept instead.
 ///
 /// # Panics
 /// If this function is called from outside of a tokio runtime.
-pub fn monitor_prompt(path: &Path, prompt_sender: watch::Sender<Box<str>>) -> anyhow::Result<()> {
-    // Normalises the path.
-    // The path is compared with to filter events later.
-    let Ok(prompt_path) = path.canonicalize() else {
-        return Err(anyhow!("Unable to get canonical path for channel prompt",));
-    };
+pub fn monitor_prompts(
+    paths: &[&Path],
+    prompt_sender: watch::Sender<Box<str>>,
+    max_bytes: u64,
+) -> anyhow::Result<()> {
+    // Normalises the paths.
+    // The paths are compared with to filter events later.
+    let mut prompt_paths: Vec<Box<Path>> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let Ok(prompt_path) = path.canonicalize() else {
+            return Err(anyhow!(
+                "Unable to get canonical path for channel prompt '{}'",
+                path.display()
+            ));
+        };
+        prompt_paths.push(prompt_path.into_boxed_path());
+    }
+
+    // Boxed to moved across threads.
+    let mut prompt_dirs: Vec<Box<Path>> = Vec::new();
+    for prompt_path in &prompt_paths {
+        let Some(parent) = prompt_path.parent() else {
+            return Err(anyhow!("Unable to get directory for channel prompt"));
+        };
+        if !prompt_dirs.iter().any(|dir| **dir == *parent) {
+            prompt_dirs.push(parent.into());
+        }
+    }
 
     let mut watcher = match RecommendedWatcher::new(
-        create_event_handler(prompt_sender.clone(), prompt_path.clone().into_boxed_path()),
+        create_event_handler(prompt_sender.clone(), prompt_paths, max_bytes),
         Config::default(),
     ) {
         Ok(var) => var,
@@ -38,18 +88,12 @@ pub fn monitor_prompt(path: &Path, prompt_sender: watch::Sender<Box<str>>) -> an
         }
     };
 
-    // Boxed to moved across threads.
-    let prompt_dir: Box<Path> = match prompt_path.parent() {
-        Some(parent) => parent.into(),
-        None => {
-            return Err(anyhow!("Unable to get directory for channel prompt"));
-        }
-    };
-
     // See watcher docs for why watching directory.
-    if let Err(err) = watcher.watch(&prompt_dir, notify::RecursiveMode::NonRecursive) {
-        return Err(anyhow!("Unable to start watching channel prompt: {err}"));
-    };
+    for prompt_dir in &prompt_dirs {
+        if let Err(err) = watcher.watch(prompt_dir, notify::RecursiveMode::NonRecursive) {
+            return Err(anyhow!("Unable to start watching channel prompt: {err}"));
+        };
+    }
 
     // Watcher needs to live for duration of program.
     tokio::spawn(async move {
@@ -61,94 +105,263 @@ pub fn monitor_prompt(path: &Path, prompt_sender: watch::Sender<Box<str>>) -> an
     Ok(())
 }
 
+/// Watches the configuration files, the receiver is notified when any of them changes.
+///
+/// Files that don't exist yet are watched as well, so they are noticed once they are created. The
+/// directories containing the files must exist.
+///
+/// # Panics
+/// If this function is called from outside of a tokio runtime.
+pub fn monitor_config(paths: &[&Path]) -> anyhow::Result<watch::Receiver<()>> {
+    let mut config_paths: Vec<PathBuf> = Vec::with_capacity(paths.len());
+    for path in paths {
+        let path = std::path::absolute(path).with_context(|| {
+            format!(
+                "Unable to get absolute path for config '{}'",
+                path.display()
+            )
+        })?;
+        // Symlinks are resolved the same way the events are resolved, if the file exists.
+        config_paths.push(path.canonicalize().unwrap_or(path));
+    }
+
+    let mut config_dirs: Vec<PathBuf> = Vec::new();
+    for config_path in &config_paths {
+        let Some(parent) = config_path.parent() else {
+            return Err(anyhow!("Unable to get directory for config"));
+        };
+        if !config_dirs.iter().any(|dir| dir == parent) {
+            config_dirs.push(parent.to_path_buf());
+        }
+    }
+
+    let (sender, receiver) = watch::channel(());
+    let event_sender = sender.clone();
+    let mut watcher = RecommendedWatcher::new(
+        move |event: Result<Event, notify::Error>| {
+            let event = match event {
+                Ok(var) => var,
+                Err(err) => {
+                    tracing::error!("Error whilst watching config files: {err}");
+                    return;
+                }
+            };
+
+            if event.kind.is_access() {
+                return;
+            }
+
+            let for_config_file = event.paths.iter().any(|path| {
+                config_paths.contains(path)
+                    || path
+                        .canonicalize()
+                        .is_ok_and(|path| config_paths.contains(&path))
+            });
+            if for_config_file {
+                event_sender.send_replace(());
+            }
+        },
+        Config::default(),
+    )
+    .context("Unable to start watcher for config")?;
+
+    for config_dir in &config_dirs {
+        watcher
+            .watch(config_dir, notify::RecursiveMode::NonRecursive)
+            .with_context(|| {
+                format!(
+                    "Unable to start watching config in '{}'",
+                    config_dir.display()
+                )
+            })?;
+    }
+
+    // The watcher needs to live as long as the changes are received.
+    tokio::spawn(async move {
+        sender.closed().await;
+        drop(watcher);
+    });
+
+    Ok(receiver)
+}
+
+/// The last read state of a single prompt file.
+struct PromptFile {
+    path: Box<Path>,
+    contents: String,
+    last_modified: io::Result<SystemTime>,
+}
+
+/// Fails with a descriptive error when a prompt file of `size` bytes is larger than `max_bytes`.
+fn check_prompt_size(path: &Path, size: u64, max_bytes: u64) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        size <= max_bytes,
+        "The prompt file at '{}' is {size} bytes, which is more than the maximum of {max_bytes} \
+         bytes. Check that `prompt_path` points at the right file, or increase `max_prompt_bytes`",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Converts the contents of a prompt file to text, failing with the position of the first invalid
+/// byte when the file is not valid UTF-8.
+fn decode_prompt(path: &Path, bytes: Vec<u8>) -> anyhow::Result<String> {
+    String::from_utf8(bytes).map_err(|err| {
+        anyhow!(
+            "The prompt file at '{}' is not valid UTF-8, the first invalid byte is at offset {}. \
+             Prompt files must be saved as UTF-8 text",
+            path.display(),
+            err.utf8_error().valid_up_to()
+        )
+    })
+}
+
+/// Reads a prompt file, unless it is larger than `max_bytes`.
+fn read_prompt_file(path: &Path, max_bytes: u64) -> anyhow::Result<String> {
+    let size = std::fs::metadata(path)?.len();
+    check_prompt_size(path, size, max_bytes)?;
+    decode_prompt(path, std::fs::read(path)?)
+}
+
+fn last_modified(path: &Path) -> io::Result<SystemTime> {
+    File::open(path)
+        .and_then(|file| file.metadata())
+        .and_then(|metadata| metadata.modified())
+}
+
 /// Creates the event handler for updating the channel prompt.
 fn create_event_handler(
     sender: watch::Sender<Box<str>>,
-    prompt_path: Box<Path>,
+    prompt_paths: Vec<Box<Path>>,
+    max_bytes: u64,
 ) -> impl FnMut(Result<Event, notify::Error>) {
-    let mut last_modified = File::open(&prompt_path)
-        .and_then(|file| file.metadata())
-        .and_then(|metadata| metadata.modified());
+    let mut files: Vec<PromptFile> = prompt_paths
+        .into_iter()
+        .map(|path| PromptFile {
+            last_modified: last_modified(&path),
+            contents: read_prompt_file(&path, max_bytes).unwrap_or_else(|err| {
+                tracing::warn!(
+                    "Unable to read prompt file at '{}', it is left out until it can be read: '{err}'",
+                    path.display()
+                );
+                String::new()
+            }),
+            path,
+        })
+        .collect();
 
     move |event| {
         let event: Event = match event {
             Ok(var) => var,
             Err(err) => {
-                tracing::error!(
-                    "Error whilst watching channel prompt file '{}'",
-                    prompt_path.display()
-                );
+                tracing::error!("Error whilst watching channel prompt files");
                 tracing::error!("{err}");
                 return;
             }
         };
 
         // Access events spam (personal experience).
-        if !(event.kind.is_modify() || event.kind.is_other()) {
+        // Create and remove events are handled since many editors delete and recreate files when
+        // saving.
+        if !(event.kind.is_modify()
+            || event.kind.is_other()
+            || event.kind.is_create()
+            || event.kind.is_remove())
+        {
             return;
         }
 
-        // Check if the event was for this channel prompt path
-        let for_prompt_file = event
-            .paths
-            .iter()
-            .filter(|path| {
-                path.canonicalize()
-                    .ok()
-                    .is_some_and(|path| *path == *prompt_path)
-            })
-            .count()
-            != 0;
-
-        if !for_prompt_file {
+        let mut updated = false;
+        for file in &mut files {
+            if update_prompt_file(file, &event, max_bytes) {
+                updated = true;
+            }
+        }
+
+        if !updated {
             return;
         }
 
-        // Check if we have read in this version of the file before
-        let modified = File::open(&prompt_path)
-            .and_then(|file| file.metadata())
-            .and_then(|metadata| metadata.modified());
-
-        match (modified, &mut last_modified) {
-            (Ok(modified), Ok(last_modified)) => {
-                if modified == *last_modified {
-                    tracing::debug!(
-                        "Prompt file '{}' has not been modified since last read. Skipping updating prompt in memory.",
-                        prompt_path.display()
-                    );
-                    return;
-                }
+        let new_prompt = files
+            .iter()
+            .map(|file| file.contents.as_str())
+            .collect::<Vec<_>>()
+            .join(PROMPT_SEPARATOR)
+            .into_boxed_str();
+        sender.send_modify(|prompt| *prompt = new_prompt);
+    }
+}
 
-                *last_modified = modified;
-            }
-            (Ok(modified), last_modified @ Err(_)) => {
-                *last_modified = Ok(modified);
-            }
-            (Err(_), Ok(_)) | (Err(_), Err(_)) => {
-                tracing::warn!(
-                    "Unable to verify if '{}' prompt file has been modified or not. Updating regardless.",
-                    prompt_path.display()
-                );
-            }
-        }
+/// Re-reads the prompt file if the event is for it. Returns whether the contents were updated.
+fn update_prompt_file(file: &mut PromptFile, event: &Event, max_bytes: u64) -> bool {
+    let prompt_path = &file.path;
+
+    // Check if the event was for this channel prompt path. Removed files can't be canonicalized,
+    // so the paths are compared as is as well.
+    let for_prompt_file = event.paths.iter().any(|path| {
+        *path == **prompt_path
+            || path
+                .canonicalize()
+                .ok()
+                .is_some_and(|path| *path == **prompt_path)
+    });
 
-        let new_prompt = match std::fs::read_to_string(&prompt_path) {
-            Ok(var) => var.into_boxed_str(),
-            Err(err) => {
-                tracing::error!(
-                    "Unable to read prompts file at '{}' : '{err}'",
+    if !for_prompt_file {
+        return false;
+    }
+
+    if event.kind.is_remove() {
+        tracing::warn!(
+            "Prompt file '{}' was removed. Keeping the previous prompt until it is recreated.",
+            prompt_path.display()
+        );
+        return false;
+    }
+
+    // Check if we have read in this version of the file before
+    let modified = last_modified(prompt_path);
+
+    match (modified, &mut file.last_modified) {
+        (Ok(modified), Ok(last_modified)) => {
+            if modified == *last_modified {
+                tracing::debug!(
+                    "Prompt file '{}' has not been modified since last read. Skipping updating prompt in memory.",
                     prompt_path.display()
                 );
-                return;
+                return false;
             }
-        };
 
-        sender.send_modify(|prompt| *prompt = new_prompt);
+            *last_modified = modified;
+        }
+        (Ok(modified), last_modified @ Err(_)) => {
+            *last_modified = Ok(modified);
+        }
+        (Err(_), Ok(_)) | (Err(_), Err(_)) => {
+            tracing::warn!(
+                "Unable to verify if '{}' prompt file has been modified or not. Updating regardless.",
+                prompt_path.display()
+            );
+        }
+    }
 
-        tracing::info!(
-            "Updated channel prompts for file at '{}'",
-            prompt_path.display()
-        );
+    match read_prompt_file(prompt_path, max_bytes) {
+        Ok(contents) => {
+            file.contents = contents;
+            tracing::info!(
+                "Updated channel prompts for file at '{}'",
+                prompt_path.display()
+            );
+            true
+        }
+        Err(err) => {
+            // Files can be briefly unreadable while an editor saves them. The previous prompt is
+            // kept so the channel can continue to respond.
+            tracing::error!(
+                "Unable to read prompts file at '{}', keeping the previous prompt: '{err}'",
+                prompt_path.display()
+            );
+            false
+        }
     }
 }
 
@@ -158,6 +371,8 @@ mod tests {
     use std::{fs::write, time::Duration};
     use tokio::time::sleep;
 
+    const MAX_BYTES: u64 = 64 * 1024;
+
     /// The text in the file must be the same as what is loaded into the prompt.
     #[tokio::test]
     async fn load_in_prompt() {
@@ -169,13 +384,42 @@ mod tests {
 
         write(prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
 
-        let (_, prompt_receiver) = load_prompt(prompt_file)
+        let (_, prompt_receiver) = load_prompts(&[prompt_file], MAX_BYTES)
             .await
             .expect("Unable to load prompt file");
 
         assert_eq!(*prompt_receiver.borrow(), "Test prompt data".into());
     }
 
+    /// Prompt files larger than the maximum must not be loaded.
+    #[tokio::test]
+    async fn oversized_prompt_rejected() {
+        let tempdir = tempfile::tempdir().expect("Unable to create temporary directory.");
+
+        let prompt_file = tempdir.path().join("prompt.txt");
+        write(&prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
+
+        let err = load_prompts(&[&prompt_file], 8)
+            .await
+            .expect_err("Oversized prompt file was loaded");
+        assert!(err.to_string().contains("more than the maximum of 8 bytes"));
+    }
+
+    /// Prompt files that are not valid UTF-8 must not be loaded, and the error must point at the
+    /// invalid byte.
+    #[tokio::test]
+    async fn invalid_utf8_prompt_rejected() {
+        let tempdir = tempfile::tempdir().expect("Unable to create temporary directory.");
+
+        let prompt_file = tempdir.path().join("prompt.txt");
+        write(&prompt_file, b"Test \xff prompt").expect("Unable to write dummy prompt data");
+
+        let err = load_prompts(&[&prompt_file], MAX_BYTES)
+            .await
+            .expect_err("Invalid prompt file was loaded");
+        assert!(err.to_string().contains("invalid byte is at offset 5"));
+    }
+
     /// When the prompt file is modified the in memory prompt must change within a reasonable time frame.
     #[tokio::test]
     async fn prompt_is_updated() {
@@ -187,11 +431,12 @@ mod tests {
 
         write(prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
 
-        let (prompt_sender, prompt_receiver) = load_prompt(prompt_file)
+        let (prompt_sender, prompt_receiver) = load_prompts(&[prompt_file], MAX_BYTES)
             .await
             .expect("Unable to load prompt file");
 
-        monitor_prompt(prompt_file, prompt_sender).expect("Unable to monitor channel prompt");
+        monitor_prompts(&[prompt_file], prompt_sender, MAX_BYTES)
+            .expect("Unable to monitor channel prompt");
 
         // Prevent race condition where file is written to before watcher inits.
         sleep(Duration::from_millis(200)).await;
@@ -215,6 +460,53 @@ mod tests {
         }
     }
 
+    /// If a prompt file is deleted and then recreated the prompt must be updated to the new contents.
+    #[tokio::test]
+    async fn prompt_is_recreated() {
+        let tempdir = tempfile::tempdir().expect("Unable to create temporary directory.");
+
+        let mut prompt_file = tempdir.path().to_path_buf();
+        prompt_file.push("prompt.txt");
+        let prompt_file = prompt_file.as_path();
+
+        write(prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
+
+        let (prompt_sender, prompt_receiver) = load_prompts(&[prompt_file], MAX_BYTES)
+            .await
+            .expect("Unable to load prompt file");
+
+        monitor_prompts(&[prompt_file], prompt_sender, MAX_BYTES)
+            .expect("Unable to monitor channel prompt");
+
+        // Prevent race condition where file is modified to before watcher inits.
+        sleep(Duration::from_millis(200)).await;
+
+        std::fs::remove_file(prompt_file).expect("Unable to remove prompt file");
+
+        // Ensure callback has enough time to run
+        sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(*prompt_receiver.borrow(), "Test prompt data".into());
+
+        write(prompt_file, "Recreated prompt data").expect("Unable to recreate prompt file");
+
+        let mut checks = 0;
+        loop {
+            sleep(Duration::from_millis(100)).await;
+
+            if *prompt_receiver.borrow() == "Recreated prompt data".into() {
+                break;
+            }
+
+            checks += 1;
+            if checks == 20 {
+                panic!(
+                    "The shared prompt was not updated within ~2 sec after the prompt file was recreated."
+                );
+            }
+        }
+    }
+
     /// If a prompt file is deleted then the old contents will remain as the prompt.
     #[tokio::test]
     async fn prompt_is_deleted() {
@@ -226,11 +518,12 @@ mod tests {
 
         write(prompt_file, "Test prompt data").expect("Unable to write dummy prompt data");
 
-        let (prompt_sender, prompt_receiver) = load_prompt(prompt_file)
+        let (prompt_sender, prompt_receiver) = load_prompts(&[prompt_file], MAX_BYTES)
             .await
             .expect("Unable to load prompt file");
 
-        monitor_prompt(prompt_file, prompt_sender).expect("Unable to monitor channel prompt");
+        monitor_prompts(&[prompt_file], prompt_sender, MAX_BYTES)
+            .expect("Unable to monitor channel prompt");
 
         // Prevent race condition where file is modified to before watcher inits.
         sleep(Duration::from_millis(200)).await;
@@ -242,4 +535,50 @@ mod tests {
 
         assert_eq!(*prompt_receiver.borrow(), "Test prompt data".into());
     }
+
+    /// Multiple prompt files must be joined in order, and changing one of them must rebuild the
+    /// prompt.
+    #[tokio::test]
+    async fn multiple_prompts_joined() {
+        let tempdir = tempfile::tempdir().expect("Unable to create temporary directory.");
+
+        let base_file = tempdir.path().join("base.txt");
+        let overlay_file = tempdir.path().join("overlay.txt");
+        write(&base_file, "Base prompt").expect("Unable to write dummy prompt data");
+        write(&overlay_file, "Overlay prompt").expect("Unable to write dummy prompt data");
+        let prompt_files = [base_file.as_path(), overlay_file.as_path()];
+
+        let (prompt_sender, prompt_receiver) = load_prompts(&prompt_files, MAX_BYTES)
+            .await
+            .expect("Unable to load prompt files");
+
+        assert_eq!(
+            *prompt_receiver.borrow(),
+            "Base prompt\n\nOverlay prompt".into()
+        );
+
+        monitor_prompts(&prompt_files, prompt_sender, MAX_BYTES)
+            .expect("Unable to monitor channel prompt");
+
+        // Prevent race condition where file is written to before watcher inits.
+        sleep(Duration::from_millis(200)).await;
+
+        write(&overlay_file, "New overlay").expect("Unable to write new prompt data");
+
+        let mut checks = 0;
+        loop {
+            sleep(Duration::from_millis(100)).await;
+
+            if *prompt_receiver.borrow() == "Base prompt\n\nNew overlay".into() {
+                break;
+            }
+
+            checks += 1;
+            if checks == 20 {
+                panic!(
+                    "The shared prompt was not updated within ~2 sec after a prompt file was updated."
+                );
+            }
+        }
+    }
 }
@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
 use tracing::error;
 use twilight_http::Client;
 use twilight_model::{
@@ -8,22 +11,151 @@ use twilight_util::builder::embed::EmbedBuilder;
 
 pub const ERROR_COLOR: u32 = 0xff_7f_7f;
 
+/// The maximum amount of characters discord allows in an embed description.
+const MAX_DESCRIPTION_CHARS: usize = 4096;
+/// The maximum amount of characters discord allows in an embed title.
+const MAX_TITLE_CHARS: usize = 256;
+/// The maximum amount of characters discord allows in a message.
+const MAX_CONTENT_CHARS: usize = 2000;
+/// Appended to errors that were too long to send.
+const TRUNCATED_MARKER: &str = "… (truncated)";
+
+/// How error messages sent in discord look.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ErrorStyle {
+    /// The color of the embed, for example `0xff7f7f`.
+    #[serde(default = "default_color")]
+    pub color: u32,
+    /// Shown above the error when set.
+    pub title: Option<String>,
+    /// If set to false, errors are sent as plain text instead of as an embed.
+    #[serde(default = "default_use_embed")]
+    pub use_embed: bool,
+}
+
+impl Default for ErrorStyle {
+    fn default() -> Self {
+        Self {
+            color: default_color(),
+            title: None,
+            use_embed: default_use_embed(),
+        }
+    }
+}
+
+impl ErrorStyle {
+    /// Checks that the configuration values are sensible.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.color <= 0xff_ff_ff,
+            "`color` must be a rgb color, at most 0xffffff"
+        );
+        if let Some(title) = &self.title {
+            anyhow::ensure!(
+                title.chars().count() <= MAX_TITLE_CHARS,
+                "`title` must be at most {MAX_TITLE_CHARS} characters"
+            );
+        }
+        Ok(())
+    }
+}
+
+fn default_color() -> u32 {
+    ERROR_COLOR
+}
+
+fn default_use_embed() -> bool {
+    true
+}
+
+/// Reports errors in discord, both in the channel where they occurred and in the error log channel.
+#[derive(Debug)]
+pub struct ErrorReporter {
+    style: ErrorStyle,
+    /// Errors from every channel are also sent here when set.
+    log_channel_id: Option<Id<ChannelMarker>>,
+}
+
+impl ErrorReporter {
+    pub fn new(style: ErrorStyle, log_channel_id: Option<Id<ChannelMarker>>) -> Self {
+        Self {
+            style,
+            log_channel_id,
+        }
+    }
+
+    /// Sends an error message in the channel where the error occurred and mirrors it to the error
+    /// log channel, mentioning the subsystem and channel it came from.
+    ///
+    /// Returns the message created in the channel where the error occurred.
+    pub async fn report(
+        &self,
+        http: &Client,
+        subsystem: &str,
+        channel_id: Id<ChannelMarker>,
+        message: &str,
+    ) -> Option<Message> {
+        let msg = send_error_msg(http, channel_id, message, &self.style).await;
+
+        if let Some(log_channel_id) = self.log_channel_id
+            && log_channel_id != channel_id
+        {
+            send_error_msg(
+                http,
+                log_channel_id,
+                &format!("{subsystem} error in <#{channel_id}>\n{message}"),
+                &self.style,
+            )
+            .await;
+        }
+
+        msg
+    }
+
+    /// Sends an error that did not occur in a channel to the error log channel, when it is set.
+    pub async fn report_global(&self, http: &Client, subsystem: &str, message: &str) {
+        if let Some(log_channel_id) = self.log_channel_id {
+            send_error_msg(
+                http,
+                log_channel_id,
+                &format!("{subsystem} error\n{message}"),
+                &self.style,
+            )
+            .await;
+        }
+    }
+}
+
 /// Utility function to send an error message in a discord channel.
 ///
-/// Logs any errors that may occur while sending the message. When successful, returns the newly
-/// created message.
+/// Messages that are too long for discord are truncated, the full message is logged instead. Logs
+/// any errors that may occur while sending the message. When successful, returns the newly created
+/// message.
 pub async fn send_error_msg(
     http: &Client,
     channel_id: Id<ChannelMarker>,
     message: &str,
+    style: &ErrorStyle,
 ) -> Option<Message> {
-    let res = http
-        .create_message(channel_id)
-        .embeds(&[EmbedBuilder::new()
-            .color(ERROR_COLOR)
-            .description(message)
-            .build()])
-        .await;
+    let res = if style.use_embed {
+        let description = truncate_error(message, MAX_DESCRIPTION_CHARS);
+        let mut embed = EmbedBuilder::new()
+            .color(style.color)
+            .description(description);
+        if let Some(title) = &style.title {
+            embed = embed.title(title);
+        }
+        http.create_message(channel_id)
+            .embeds(&[embed.build()])
+            .await
+    } else {
+        let content = match &style.title {
+            Some(title) => format!("**{title}**\n{message}"),
+            None => message.to_string(),
+        };
+        let content = truncate_error(&content, MAX_CONTENT_CHARS);
+        http.create_message(channel_id).content(&content).await
+    };
     let res = match res {
         Ok(res) => res,
         Err(err) => {
@@ -40,3 +172,38 @@ pub async fn send_error_msg(
         }
     }
 }
+
+/// Shortens the message to at most `max` characters, ending with a marker when it was truncated.
+///
+/// Error chains can contain entire responses of other APIs, which would make sending the error
+/// itself fail. The full message is logged when it is truncated.
+fn truncate_error(message: &str, max: usize) -> Cow<'_, str> {
+    let keep = max - TRUNCATED_MARKER.chars().count();
+    match message.char_indices().nth(keep) {
+        Some((end, _)) if message[end..].chars().count() > TRUNCATED_MARKER.chars().count() => {
+            error!("Error message too long for discord, it was truncated: {message}");
+            Cow::Owned(format!("{}{TRUNCATED_MARKER}", &message[..end]))
+        }
+        _ => Cow::Borrowed(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_error_unchanged() {
+        let message = "a".repeat(MAX_DESCRIPTION_CHARS);
+        assert_eq!(truncate_error(&message, MAX_DESCRIPTION_CHARS), message);
+    }
+
+    #[test]
+    fn oversized_error_truncated() {
+        let message = format!("request failed: {}", "{\"error\": \"é\"}".repeat(1000));
+        let truncated = truncate_error(&message, MAX_DESCRIPTION_CHARS);
+        assert_eq!(truncated.chars().count(), MAX_DESCRIPTION_CHARS);
+        assert!(truncated.starts_with("request failed: {"));
+        assert!(truncated.ends_with(TRUNCATED_MARKER));
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use anyhow::Context;
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use serde::{Deserialize, Serialize};
+use tokio::{net::TcpListener, sync::watch};
+use tracing::info;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Configuration {
+    /// The address the health check server listens on.
+    #[serde(default = "default_bind_address")]
+    bind_address: SocketAddr,
+}
+
+fn default_bind_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 8080))
+}
+
+/// The connection status of the shards, shared between the event handlers and the health check
+/// server.
+#[derive(Debug)]
+pub struct Status {
+    shard_count: usize,
+    connected_shards: AtomicUsize,
+    has_been_ready: AtomicBool,
+}
+
+impl Status {
+    pub fn new(shard_count: usize) -> Self {
+        Self {
+            shard_count,
+            connected_shards: AtomicUsize::new(0),
+            has_been_ready: AtomicBool::new(false),
+        }
+    }
+
+    /// Marks a shard as connected, this should be called when it receives `Ready` or `Resumed`.
+    pub fn shard_connected(&self) {
+        self.connected_shards.fetch_add(1, Ordering::Relaxed);
+        self.has_been_ready.store(true, Ordering::Relaxed);
+    }
+
+    /// Marks a previously connected shard as disconnected.
+    pub fn shard_disconnected(&self) {
+        self.connected_shards.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Whether the bot has connected to discord at least once.
+    fn is_live(&self) -> bool {
+        self.has_been_ready.load(Ordering::Relaxed)
+    }
+
+    /// Whether all shards are currently connected.
+    fn is_ready(&self) -> bool {
+        self.connected_shards.load(Ordering::Relaxed) == self.shard_count
+    }
+}
+
+/// Serves the `/healthz` and `/readyz` endpoints until `shutdown` is set to true.
+///
+/// `/healthz` succeeds once any shard has been ready, `/readyz` succeeds while all shards are
+/// connected.
+pub async fn serve(
+    config: Configuration,
+    status: Arc<Status>,
+    mut shutdown: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(status);
+
+    let listener = TcpListener::bind(config.bind_address)
+        .await
+        .with_context(|| format!("failed to bind to {}", config.bind_address))?;
+    info!("Serving health checks on {}", config.bind_address);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async move {
+            _ = shutdown.wait_for(|shutdown| *shutdown).await;
+        })
+        .await
+        .context("health check server failed")
+}
+
+async fn healthz(State(status): State<Arc<Status>>) -> StatusCode {
+    if status.is_live() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+async fn readyz(State(status): State<Arc<Status>>) -> StatusCode {
+    if status.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -1,94 +1,449 @@
 mod ai_channel;
+mod commands;
 mod config;
 mod error;
+mod health;
+mod presence;
+mod prometheus;
+mod reload;
 
-use std::{path::Path, sync::Arc};
-use tokio::{select, sync::broadcast};
-use tracing::{error, info, level_filters::LevelFilter};
+use anyhow::{Context, anyhow};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    select,
+    sync::{broadcast, watch},
+    task::JoinSet,
+    time::{Instant, timeout},
+};
+use tracing::{Instrument, error, info, info_span, level_filters::LevelFilter, warn};
 use tracing_subscriber::{EnvFilter, filter::Directive};
-use twilight_cache_inmemory::{DefaultInMemoryCache, InMemoryCache, ResourceType};
+use twilight_cache_inmemory::{DefaultInMemoryCache, InMemoryCache};
 use twilight_gateway::{
-    CloseFrame, Event, EventTypeFlags, Intents, Shard, ShardId, StreamExt as _,
+    CloseFrame, ConfigBuilder, Event, EventTypeFlags, Intents, Shard, ShardId, StreamExt as _,
+    create_iterator, create_recommended,
 };
 use twilight_http::Client as HttpClient;
+use twilight_model::{gateway::CloseCode, oauth::ApplicationFlags};
+
+/// Set when the bot is shutting down, this stops the shards from reconnecting once closed.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/// The amount of times any shard has reconnected to the gateway.
+static RECONNECTS: AtomicU64 = AtomicU64::new(0);
+
+/// The connection of a shard is reported as unhealthy after this many errors in a row.
+const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+
+/// Errors only count as consecutive when they happen within this time of the first one.
+const CONSECUTIVE_ERRORS_WINDOW: Duration = Duration::from_secs(60);
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_default_directive(Directive::from(LevelFilter::INFO))
-                .from_env_lossy(),
-        )
-        .init();
-
-    let config = config::Configuration::read_with_env("CONFIG_PATH", [Path::new("bot.toml")])?;
+    init_logging();
 
-    let shard = Shard::new(
-        ShardId::ONE,
-        config.token.clone(),
-        Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT,
-    );
-    let shard_sender = shard.sender();
+    let config_locations =
+        config::locations("CONFIG_PATH", config::DEFAULT_LOCATIONS.map(Path::new));
+    let config = config::Configuration::read(config_locations.iter().map(PathBuf::as_path)).await?;
+    if env::args().any(|arg| arg == "--print-config")
+        || env::var("BOT_PRINT_CONFIG").is_ok_and(|value| value == "1")
+    {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
+    }
+    config.validate().context("invalid configuration")?;
+    config.log_summary();
 
-    let http = Arc::new(HttpClient::builder().token(config.token).build());
+    if let Some(metrics) = &config.metrics {
+        metrics.install()?;
+    }
 
+    let resource_types = config.resource_types();
+    info!("Caching resource types: {resource_types:?}");
     let cache = Arc::new(
         DefaultInMemoryCache::builder()
-            .resource_types(ResourceType::empty())
+            .resource_types(resource_types)
             .build(),
     );
 
+    let http = Arc::new(HttpClient::builder().token(config.token.clone()).build());
+    // Used to report gateway errors, changes to the error settings apply after a restart.
+    let errors = Arc::new(error::ErrorReporter::new(
+        config.errors.clone(),
+        config.error_log_channel_id,
+    ));
+
+    let intents = config.intents();
+    info!("Requesting gateway intents: {intents:?}");
+    let mut gateway_config = ConfigBuilder::new(config.token.clone(), intents);
+    if let Some(presence) = &config.presence {
+        gateway_config = gateway_config.presence(presence.payload()?);
+    }
+    let gateway_config = gateway_config.build();
+    let shards: Vec<Shard> = match config.shard_count {
+        Some(shard_count) => {
+            create_iterator(0..shard_count, shard_count, gateway_config, |_, builder| {
+                builder.build()
+            })
+            .collect()
+        }
+        None => create_recommended(&http, gateway_config, |_, builder| builder.build())
+            .await
+            .context("failed to get the recommended shard count")?
+            .collect(),
+    };
+    info!("Starting {} shard(s)", shards.len());
+    let shard_senders: Vec<_> = shards.iter().map(Shard::sender).collect();
+    let status = Arc::new(health::Status::new(shards.len()));
+
+    // Set to true when the bot is shutting down.
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+
+    let health_task = config.health.clone().map(|health_config| {
+        let status = status.clone();
+        let shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(
+            async move {
+                if let Err(err) = health::serve(health_config, status, shutdown_rx).await {
+                    error!("{err:?}");
+                }
+            }
+            .instrument(info_span!("health")),
+        )
+    });
+
     // All incoming events are sent through the broadcast channel and each event is handled by every
     // task that handles events.
-    let (event_tx, event_rx) = broadcast::channel(16);
+    let (event_tx, _) = broadcast::channel(config.event_capacity);
 
-    info!("Serving {} AI channel(s)", config.ai_channels.len());
-    for ai_channel_config in config.ai_channels {
-        tokio::spawn(ai_channel::serve(
-            ai_channel_config,
-            event_rx.resubscribe(),
+    // The AI channels that are running, updated when AI channels are started or stopped.
+    let (command_channels_tx, command_channels) = watch::channel(Vec::new());
+    let (shared_tx, shared) = watch::channel(reload::ai_channel_shared(&config));
+    // Set by the pause command, stops every AI channel from sending requests to the LLM.
+    let paused = Arc::new(AtomicBool::new(false));
+    tokio::spawn(
+        commands::serve(
+            event_tx.subscribe(),
             http.clone(),
-        ));
-    }
+            cache.clone(),
+            command_channels,
+            shared,
+            paused.clone(),
+        )
+        .instrument(info_span!("commands")),
+    );
+
+    let mut ai_channels = reload::AiChannels::new(
+        event_tx.clone(),
+        http.clone(),
+        cache.clone(),
+        shared_tx,
+        paused,
+        command_channels_tx,
+    );
+    ai_channels.update(config.ai_channels.clone(), None);
+    // Changes to the configuration are applied while running, this also stops the AI channels when
+    // shutting down.
+    let reload_task = tokio::spawn(
+        reload::watch_config(
+            config_locations,
+            config,
+            ai_channels,
+            shard_senders.clone(),
+            shutdown_rx.clone(),
+        )
+        .instrument(info_span!("reload")),
+    );
 
     info!("Listening for events");
+    let mut shard_tasks = JoinSet::new();
+    for shard in shards {
+        let span = info_span!("shard", shard_id = shard.id().number());
+        shard_tasks.spawn(
+            handle_events(
+                shard,
+                cache.clone(),
+                event_tx.clone(),
+                status.clone(),
+                http.clone(),
+                errors.clone(),
+            )
+            .instrument(span),
+        );
+    }
+
+    // A shard that can't connect because of a fatal error stops the bot, so a supervisor can
+    // restart it instead of the bot running without receiving events.
+    let mut result = Ok(());
     select! {
-        _ = handle_events(shard, cache, event_tx) => {},
+        res = join_shards(&mut shard_tasks) => result = res,
         res = await_exit_signal() => {
             if let Err(err) = res {
                 error!("error waiting exit signal: {err}");
             }
         },
     }
-    _ = shard_sender.close(CloseFrame::NORMAL);
+    if let Err(err) = &result {
+        error!("Shutting down: {err}");
+    }
+
+    SHUTDOWN.store(true, Ordering::Relaxed);
+    _ = shutdown_tx.send(true);
+    for sender in &shard_senders {
+        _ = sender.close(CloseFrame::NORMAL);
+    }
+    // Give the shards some time to close their connection.
+    _ = timeout(Duration::from_secs(5), join_shards(&mut shard_tasks)).await;
+    // Let the AI channels send the responses they are generating, without letting a stuck LLM
+    // request block the shutdown.
+    if timeout(Duration::from_secs(20), reload_task).await.is_err() {
+        warn!("Not all AI channels finished their responses in time");
+    }
+    if let Some(health_task) = health_task {
+        _ = health_task.await;
+    }
+    result
+}
+
+/// Initializes logging using the format set in the `LOG_FORMAT` environment variable.
+///
+/// Supported formats are `full` (the default), `compact`, `pretty` and `json`. The log level can be
+/// changed using the `RUST_LOG` environment variable regardless of format.
+fn init_logging() {
+    let subscriber = tracing_subscriber::fmt::fmt().with_env_filter(
+        EnvFilter::builder()
+            .with_default_directive(Directive::from(LevelFilter::INFO))
+            .from_env_lossy(),
+    );
+
+    let log_format = env::var("LOG_FORMAT").ok();
+    match log_format.as_deref() {
+        Some("json") => subscriber.json().init(),
+        Some("pretty") => subscriber.pretty().init(),
+        Some("compact") => subscriber.compact().init(),
+        None | Some("full") => subscriber.init(),
+        Some(unknown) => {
+            subscriber.init();
+            warn!("Unknown LOG_FORMAT '{unknown}', using the full format instead");
+        }
+    }
+}
+
+/// Waits for all shards to stop.
+///
+/// Returns early with the error of the first shard that stopped because of a fatal error.
+async fn join_shards(tasks: &mut JoinSet<anyhow::Result<()>>) -> anyhow::Result<()> {
+    while let Some(res) = tasks.join_next().await {
+        if let Ok(Err(err)) = res {
+            return Err(err);
+        }
+    }
     Ok(())
 }
 
 /// Listen for discord events and broadcast them to all event handlers.
+///
+/// Returns an error when the gateway closed the connection with a code the shard can't reconnect
+/// after, like when the token is invalid. Repeated errors receiving events are reported in the
+/// error log channel.
 async fn handle_events(
     mut shard: Shard,
     cache: Arc<InMemoryCache>,
     event_tx: broadcast::Sender<Arc<Event>>,
-) {
+    status: Arc<health::Status>,
+    http: Arc<HttpClient>,
+    errors: Arc<error::ErrorReporter>,
+) -> anyhow::Result<()> {
+    // Used to tell the first connection apart from reconnects.
+    let mut has_connected = false;
+    // Whether the shard currently has an active session.
+    let mut is_connected = false;
+    // The errors received in a row, and when the first of them was received.
+    let mut consecutive_errors = 0;
+    let mut first_error_at = Instant::now();
+
     while let Some(item) = shard.next_event(EventTypeFlags::all()).await {
-        let Ok(event) = item else {
-            tracing::warn!(source = ?item.unwrap_err(), "error receiving event");
+        let event = match item {
+            Ok(event) => {
+                consecutive_errors = 0;
+                event
+            }
+            Err(err) => {
+                warn!(source = ?err, "error receiving event");
 
-            continue;
+                if consecutive_errors == 0 || first_error_at.elapsed() > CONSECUTIVE_ERRORS_WINDOW {
+                    consecutive_errors = 0;
+                    first_error_at = Instant::now();
+                }
+                consecutive_errors += 1;
+                if consecutive_errors == MAX_CONSECUTIVE_ERRORS {
+                    error!(
+                        shard = %shard.id(),
+                        "Received {consecutive_errors} errors in a row, the gateway connection is \
+                         unhealthy: {err}"
+                    );
+                    errors
+                        .report_global(
+                            &http,
+                            "Gateway",
+                            &format!(
+                                "Shard {} received {consecutive_errors} errors in a row, the \
+                                 gateway connection is unhealthy\n```\n{err}\n```",
+                                shard.id()
+                            ),
+                        )
+                        .await;
+                }
+                continue;
+            }
         };
 
-        if let Event::GatewayClose(Some(info)) = &event {
-            error!(code = info.code, reason = %info.reason, "Gateway connection closed");
+        // Shards reconnect after being closed, unless the bot is shutting down.
+        if matches!(event, Event::GatewayClose(_)) && SHUTDOWN.load(Ordering::Relaxed) {
+            break;
+        }
+
+        log_connection_event(shard.id(), &event, &mut has_connected);
+
+        if let Event::Ready(ready) = &event {
+            check_message_content_intent(
+                shard.id(),
+                shard.config().intents(),
+                ready.application.flags,
+            );
+        }
+
+        match &event {
+            Event::Ready(_) | Event::Resumed if !is_connected => {
+                is_connected = true;
+                status.shard_connected();
+            }
+            Event::GatewayClose(_) if is_connected => {
+                is_connected = false;
+                status.shard_disconnected();
+            }
+            _ => {}
         }
 
         // Update the cache with the event.
         cache.update(&event);
 
+        let fatal_code = match &event {
+            Event::GatewayClose(Some(frame))
+                if CloseCode::try_from(frame.code).is_ok_and(|code| !code.can_reconnect()) =>
+            {
+                Some(frame.code)
+            }
+            _ => None,
+        };
+
         // Wrap the event in Arc. Since there will be multiple receivers, this prevents the value
         // from needing to be deeply cloned for each receiver.
         let event = Arc::new(event);
         _ = event_tx.send(event);
+
+        if let Some(code) = fatal_code {
+            return Err(anyhow!(
+                "shard {} was closed by discord with the fatal close code {code}",
+                shard.id()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Logs that a feature fell behind and skipped `skipped` events.
+///
+/// This happens when a feature takes longer to handle events than it takes to receive
+/// `event_capacity` new events, for example during a burst of messages.
+pub fn log_lagged_events(feature: &'static str, skipped: u64) {
+    metrics::counter!("bot_events_lagged_total", "feature" => feature).increment(skipped);
+    warn!(
+        feature,
+        skipped,
+        "Fell behind handling events and skipped some, consider increasing `event_capacity`"
+    );
+}
+
+/// Warns when the message content intent is needed, but it is not enabled for the bot.
+///
+/// Discord then sends messages without their content, which makes the AI channels see empty
+/// messages.
+fn check_message_content_intent(shard_id: ShardId, intents: Intents, flags: ApplicationFlags) {
+    let enabled = flags.intersects(
+        ApplicationFlags::GATEWAY_MESSAGE_CONTENT
+            | ApplicationFlags::GATEWAY_MESSAGE_CONTENT_LIMITED,
+    );
+    if intents.contains(Intents::MESSAGE_CONTENT) && !enabled {
+        error!(
+            shard = %shard_id,
+            "The \"Message Content Intent\" is not enabled for the bot, it can't read messages. \
+             Enable it in the discord developer portal"
+        );
+    }
+}
+
+/// Logs changes to the state of the gateway connection of a shard.
+fn log_connection_event(shard_id: ShardId, event: &Event, has_connected: &mut bool) {
+    match event {
+        Event::Ready(ready) => {
+            if *has_connected {
+                metrics::counter!("bot_gateway_reconnects_total").increment(1);
+                let reconnects = RECONNECTS.fetch_add(1, Ordering::Relaxed) + 1;
+                info!(shard = %shard_id, reconnects, "Reconnected with a new session");
+            }
+            *has_connected = true;
+
+            info!(
+                shard = %shard_id,
+                session_id = %ready.session_id,
+                resume_gateway_url = %ready.resume_gateway_url,
+                "Shard is ready"
+            );
+        }
+        Event::Resumed => {
+            metrics::counter!("bot_gateway_reconnects_total").increment(1);
+            let reconnects = RECONNECTS.fetch_add(1, Ordering::Relaxed) + 1;
+            info!(shard = %shard_id, reconnects, "Resumed the previous session");
+        }
+        Event::GatewayClose(Some(info)) => {
+            error!(
+                shard = %shard_id,
+                code = info.code,
+                reason = %info.reason,
+                "Gateway connection closed"
+            );
+
+            match CloseCode::try_from(info.code) {
+                Ok(CloseCode::DisallowedIntents) => {
+                    error!(
+                        shard = %shard_id,
+                        "Discord refused the gateway intents, enable the \"Message Content Intent\" \
+                         for the bot in the discord developer portal. The shard will not reconnect"
+                    );
+                }
+                Ok(code) if !code.can_reconnect() => {
+                    error!(shard = %shard_id, "The close code is fatal, the shard will not reconnect");
+                }
+                Ok(CloseCode::InvalidSequence | CloseCode::SessionTimedOut) => {
+                    warn!(
+                        shard = %shard_id,
+                        "The session can't be resumed, a new session will be identified"
+                    );
+                }
+                _ => {
+                    info!(shard = %shard_id, "The shard will attempt to resume the session");
+                }
+            }
+        }
+        _ => {}
     }
 }
 
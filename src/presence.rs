@@ -0,0 +1,65 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use twilight_model::gateway::{
+    payload::outgoing::update_presence::UpdatePresencePayload,
+    presence::{ActivityType, MinimalActivity, Status},
+};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Configuration {
+    /// The online status of the bot: `online`, `idle`, `dnd` or `invisible`.
+    #[serde(default = "default_status")]
+    status: Status,
+    /// The activity shown below the bot's name.
+    activity: Activity,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+struct Activity {
+    #[serde(rename = "type")]
+    kind: ActivityKind,
+    /// The text shown for the activity, for example the name of the game being played.
+    name: String,
+    /// The stream url, only used by the `streaming` activity type.
+    url: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum ActivityKind {
+    Playing,
+    Streaming,
+    Listening,
+    Watching,
+    Competing,
+}
+
+impl From<ActivityKind> for ActivityType {
+    fn from(kind: ActivityKind) -> Self {
+        match kind {
+            ActivityKind::Playing => ActivityType::Playing,
+            ActivityKind::Streaming => ActivityType::Streaming,
+            ActivityKind::Listening => ActivityType::Listening,
+            ActivityKind::Watching => ActivityType::Watching,
+            ActivityKind::Competing => ActivityType::Competing,
+        }
+    }
+}
+
+fn default_status() -> Status {
+    Status::Online
+}
+
+impl Configuration {
+    /// Creates the presence payload that is sent to discord.
+    pub fn payload(&self) -> anyhow::Result<UpdatePresencePayload> {
+        let activity = MinimalActivity {
+            kind: self.activity.kind.into(),
+            name: self.activity.name.clone(),
+            url: self.activity.url.clone(),
+        };
+
+        UpdatePresencePayload::new(vec![activity.into()], false, None, self.status)
+            .context("invalid presence configuration")
+    }
+}
@@ -0,0 +1,67 @@
+use std::net::SocketAddr;
+
+use anyhow::Context;
+use metrics::{describe_counter, describe_histogram};
+use metrics_exporter_prometheus::PrometheusBuilder;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Configuration {
+    /// The address the `/metrics` endpoint listens on.
+    #[serde(default = "default_bind_address")]
+    bind_address: SocketAddr,
+}
+
+fn default_bind_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9000))
+}
+
+impl Configuration {
+    /// Installs the prometheus exporter as the global metrics recorder and starts serving it.
+    ///
+    /// Metrics are only recorded after this has been called. Until then, recording a metric does
+    /// nothing.
+    ///
+    /// # Panics
+    /// If this function is called from outside of a tokio runtime.
+    pub fn install(&self) -> anyhow::Result<()> {
+        PrometheusBuilder::new()
+            .with_http_listener(self.bind_address)
+            .install()
+            .context("failed to start the prometheus exporter")?;
+
+        describe_histogram!(
+            "bot_llm_response_seconds",
+            metrics::Unit::Seconds,
+            "Time taken by the LLM api to generate a response"
+        );
+        describe_counter!(
+            "bot_llm_errors_total",
+            "Amount of failed attempts to generate a response"
+        );
+        describe_counter!(
+            "bot_llm_empty_responses_total",
+            "Amount of responses in which the LLM chose to stay silent using <empty/>"
+        );
+        describe_counter!(
+            "bot_messages_queued_total",
+            "Amount of messages queued to be seen by the LLM"
+        );
+        describe_counter!(
+            "bot_messages_dropped_total",
+            "Amount of messages dropped because the queue was full"
+        );
+        describe_counter!(
+            "bot_gateway_reconnects_total",
+            "Amount of times a shard reconnected to the discord gateway"
+        );
+        describe_counter!(
+            "bot_events_lagged_total",
+            "Amount of events skipped because a feature fell behind handling them"
+        );
+
+        info!("Serving metrics on {}", self.bind_address);
+        Ok(())
+    }
+}
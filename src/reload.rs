@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::AtomicBool},
+    time::Duration,
+};
+
+use tokio::{
+    select,
+    sync::{broadcast, watch},
+    task::JoinSet,
+    time::sleep,
+};
+use tracing::{Instrument, error, info, info_span, warn};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_gateway::{Event, MessageSender};
+use twilight_http::Client;
+use twilight_model::{
+    gateway::{OpCode, payload::outgoing::UpdatePresence},
+    id::{Id, marker::ChannelMarker},
+};
+
+use crate::{
+    ai_channel,
+    config::{self, Configuration, file_watch::monitor_config},
+    error::ErrorReporter,
+};
+
+/// The time to wait after a change before reading the configuration, as editors often write a
+/// file in multiple steps.
+const RELOAD_DELAY: Duration = Duration::from_millis(500);
+
+/// Creates the state shared by the AI channels from the configuration.
+pub fn ai_channel_shared(config: &Configuration) -> Arc<ai_channel::Shared> {
+    Arc::new(ai_channel::Shared {
+        guild_overrides: config
+            .guilds
+            .iter()
+            .map(|guild| (guild.guild_id, guild.ai_channel.clone()))
+            .collect(),
+        errors: ErrorReporter::new(config.errors.clone(), config.error_log_channel_id),
+        command_prefix: config.command_prefix.clone(),
+        owners: config.owners.clone(),
+    })
+}
+
+/// Starts and stops the AI channels, so they can be changed while the bot is running.
+pub struct AiChannels {
+    events: broadcast::Sender<Arc<Event>>,
+    http: Arc<Client>,
+    cache: Arc<InMemoryCache>,
+    /// The state shared by the AI channels, also used by the commands.
+    shared: watch::Sender<Arc<ai_channel::Shared>>,
+    /// Set while the AI channels are paused, this is kept when channels are restarted.
+    paused: Arc<AtomicBool>,
+    running: HashMap<Id<ChannelMarker>, RunningChannel>,
+    tasks: JoinSet<()>,
+    /// The running channels and whether their threads are included, this is used by the commands.
+    command_channels: watch::Sender<Vec<(Id<ChannelMarker>, bool)>>,
+}
+
+struct RunningChannel {
+    config: ai_channel::Configuration,
+    /// Set to true to stop the channel.
+    stop: watch::Sender<bool>,
+}
+
+impl AiChannels {
+    pub fn new(
+        events: broadcast::Sender<Arc<Event>>,
+        http: Arc<Client>,
+        cache: Arc<InMemoryCache>,
+        shared: watch::Sender<Arc<ai_channel::Shared>>,
+        paused: Arc<AtomicBool>,
+        command_channels: watch::Sender<Vec<(Id<ChannelMarker>, bool)>>,
+    ) -> Self {
+        Self {
+            events,
+            http,
+            cache,
+            shared,
+            paused,
+            running: HashMap::new(),
+            tasks: JoinSet::new(),
+            command_channels,
+        }
+    }
+
+    /// Starts, restarts and stops AI channels so the running channels match `configs`.
+    ///
+    /// Channels with an unchanged configuration keep running, unless a new `shared` state is given.
+    /// Restarted channels start without history.
+    pub fn update(
+        &mut self,
+        configs: Vec<ai_channel::Configuration>,
+        shared: Option<Arc<ai_channel::Shared>>,
+    ) {
+        // Clean up the tasks of channels that were stopped before.
+        while self.tasks.try_join_next().is_some() {}
+
+        let restart_all = shared.is_some();
+        if let Some(shared) = shared {
+            self.shared.send_replace(shared);
+        }
+
+        let removed: Vec<_> = self
+            .running
+            .keys()
+            .filter(|channel_id| {
+                !configs
+                    .iter()
+                    .any(|config| config.get_channel_id() == *channel_id)
+            })
+            .copied()
+            .collect();
+        for channel_id in removed {
+            info!("Stopping AI channel {channel_id}, it was removed from the configuration");
+            self.stop(channel_id);
+        }
+
+        for config in configs {
+            let channel_id = *config.get_channel_id();
+            let running_config = self.running.get(&channel_id).map(|running| &running.config);
+            match running_config {
+                Some(running_config) if *running_config == config && !restart_all => continue,
+                Some(_) => {
+                    info!("Restarting AI channel {channel_id} to apply the changed configuration");
+                    self.stop(channel_id);
+                }
+                None => info!("Starting AI channel {channel_id}"),
+            }
+            self.start(config);
+        }
+
+        self.command_channels.send_replace(
+            self.running
+                .values()
+                .map(|running| {
+                    (
+                        *running.config.get_channel_id(),
+                        running.config.get_include_threads(),
+                    )
+                })
+                .collect(),
+        );
+    }
+
+    fn start(&mut self, config: ai_channel::Configuration) {
+        let (stop, stop_rx) = watch::channel(false);
+        // Every log line of the channel includes the channel it came from. The span has no parent,
+        // so channels restarted by a reload aren't logged as part of the reload.
+        let span = info_span!(parent: None, "ai_channel", channel_id = %config.get_channel_id());
+        self.tasks.spawn(
+            ai_channel::serve(
+                config.clone(),
+                self.events.subscribe(),
+                self.http.clone(),
+                self.cache.clone(),
+                self.shared.borrow().clone(),
+                self.paused.clone(),
+                stop_rx,
+            )
+            .instrument(span),
+        );
+        self.running
+            .insert(*config.get_channel_id(), RunningChannel { config, stop });
+    }
+
+    /// Stops the channel, the response it is generating is still sent.
+    fn stop(&mut self, channel_id: Id<ChannelMarker>) {
+        if let Some(running) = self.running.remove(&channel_id) {
+            running.stop.send_replace(true);
+        }
+    }
+
+    /// Stops all AI channels and waits for the responses they are generating to be sent.
+    pub async fn shutdown(&mut self) {
+        for (_, running) in self.running.drain() {
+            running.stop.send_replace(true);
+        }
+        while self.tasks.join_next().await.is_some() {}
+    }
+}
+
+/// Applies changes to the configuration files while the bot is running.
+///
+/// Remote locations are read again when one of the files changes, but are not watched themselves.
+/// Returns once `shutdown` is set to true and the AI channels have stopped.
+pub async fn watch_config(
+    locations: Vec<PathBuf>,
+    mut current: Configuration,
+    mut ai_channels: AiChannels,
+    shard_senders: Vec<MessageSender>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let files: Vec<&Path> = locations
+        .iter()
+        .map(PathBuf::as_path)
+        .filter(|location| config::remote_url(location).is_none())
+        .collect();
+    let mut changes = match monitor_config(&files) {
+        Ok(changes) => Some(changes),
+        Err(err) => {
+            error!(
+                "Unable to watch the configuration, changes are not applied until the bot is restarted: {err:?}"
+            );
+            None
+        }
+    };
+
+    loop {
+        let changed = select! {
+            _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            changed = async {
+                match &mut changes {
+                    Some(changes) => changes.changed().await.is_ok(),
+                    None => std::future::pending().await,
+                }
+            } => changed,
+        };
+        if !changed {
+            changes = None;
+            continue;
+        }
+
+        sleep(RELOAD_DELAY).await;
+        if let Some(changes) = &mut changes {
+            // Changes made while waiting are included in this reload.
+            changes.borrow_and_update();
+        }
+
+        info!("The configuration changed, reloading it");
+        let new = match Configuration::read(locations.iter().map(PathBuf::as_path)).await {
+            Ok(new) => new,
+            Err(err) => {
+                error!(
+                    "Unable to read the changed configuration, keeping the previous one: {err:?}"
+                );
+                continue;
+            }
+        };
+        if let Err(err) = new.validate() {
+            error!("The changed configuration is invalid, keeping the previous one: {err:?}");
+            continue;
+        }
+
+        apply(&current, &new, &mut ai_channels, &shard_senders);
+        current = new;
+    }
+
+    ai_channels.shutdown().await;
+}
+
+/// Applies the differences between the running and the new configuration.
+fn apply(
+    current: &Configuration,
+    new: &Configuration,
+    ai_channels: &mut AiChannels,
+    shard_senders: &[MessageSender],
+) {
+    let mut needs_restart = Vec::new();
+    if new.token != current.token {
+        needs_restart.push("`token`");
+    }
+    if new.shard_count != current.shard_count {
+        needs_restart.push("`shard_count`");
+    }
+    if new.event_capacity != current.event_capacity {
+        needs_restart.push("`event_capacity`");
+    }
+    if new.health != current.health {
+        needs_restart.push("`health`");
+    }
+    if new.metrics != current.metrics {
+        needs_restart.push("`metrics`");
+    }
+    if new.intents() != current.intents() || new.resource_types() != current.resource_types() {
+        needs_restart.push("the events and cache needed by the AI channels");
+    }
+
+    if new.presence != current.presence {
+        match new.presence.as_ref().map(|presence| presence.payload()) {
+            Some(Ok(payload)) => {
+                let update = UpdatePresence {
+                    d: payload,
+                    op: OpCode::PresenceUpdate,
+                };
+                for sender in shard_senders {
+                    if let Err(err) = sender.command(&update) {
+                        error!("Failed to update the presence: {err}");
+                    }
+                }
+                info!("Updated the presence");
+            }
+            Some(Err(err)) => error!("Failed to update the presence: {err:?}"),
+            // Discord's default presence is only shown again after reconnecting.
+            None => needs_restart.push("`presence`"),
+        }
+    }
+
+    if !needs_restart.is_empty() {
+        warn!(
+            "The changes to {} can only be applied by restarting the bot",
+            needs_restart.join(", ")
+        );
+    }
+
+    let shared_changed = new.guilds != current.guilds
+        || new.errors != current.errors
+        || new.error_log_channel_id != current.error_log_channel_id
+        || new.command_prefix != current.command_prefix
+        || new.owners != current.owners;
+    ai_channels.update(
+        new.ai_channels.clone(),
+        shared_changed.then(|| ai_channel_shared(new)),
+    );
+}